@@ -1,17 +1,20 @@
+use crate::acme::STATE_DIRECTORY;
+use arc_swap::ArcSwapOption;
 use lazy_static::lazy_static;
 use pem::parse_many;
 use rustls::sign::{any_ecdsa_type, CertifiedKey};
 use rustls::{Certificate, PrivateKey};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
-use std::sync::RwLock;
-use tokio::time::Instant;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tokio::fs::{create_dir_all, read, write};
 
 lazy_static! {
     static ref ACCOUNT_KEYS: RwLock<Option<Vec<u8>>> = RwLock::new(None);
     static ref ACCOUNT_KID: RwLock<Option<Vec<u8>>> = RwLock::new(None);
     static ref CHALLENGE_KEY: RwLock<Option<HashMap<String, CertifiedKey>>> = RwLock::new(None);
-    static ref CERTIFICATE: RwLock<Option<(CertifiedKey, Instant)>> = RwLock::new(None);
+    static ref CERTIFICATE: ArcSwapOption<CertifiedKey> = ArcSwapOption::empty();
 }
 
 pub async fn restore_account_keys() -> Option<Vec<u8>> {
@@ -80,13 +83,45 @@ pub fn set_certificate(pem: &[u8]) -> Result<()> {
             .into_iter()
             .map(|pem| Certificate(pem.contents))
             .collect();
-        *CERTIFICATE
-            .write()
-            .map_err(|_err| Error::new(ErrorKind::Other, "Failed to write certificate chain"))? =
-            Some((CertifiedKey::new(chain, key), Instant::now()));
+        CERTIFICATE.store(Some(Arc::new(CertifiedKey::new(chain, key))));
         Ok(())
     }
 }
-pub fn get_certificate() -> Option<(CertifiedKey, Instant)> {
-    CERTIFICATE.read().map_or(None, |it| it.clone())
+pub fn get_certificate() -> Option<Arc<CertifiedKey>> {
+    CERTIFICATE.load_full()
+}
+
+pub async fn restore_ocsp_response(serial: &str) -> Option<Vec<u8>> {
+    read(Path::new(STATE_DIRECTORY.as_str()).join(format!("ocsp/{}.der", serial)))
+        .await
+        .ok()
+}
+pub async fn backup_ocsp_response(serial: &str, bytes: &[u8]) -> Result<()> {
+    let dir = Path::new(STATE_DIRECTORY.as_str()).join("ocsp");
+    create_dir_all(&dir).await?;
+    write(dir.join(format!("{}.der", serial)), bytes).await
+}
+pub fn set_ocsp_response(certificate: &[u8], bytes: Vec<u8>) -> Result<()> {
+    let mut stapled = false;
+    CERTIFICATE.rcu(|current| match current {
+        Some(key) if key.cert.first().map(|it| it.0.as_slice()) == Some(certificate) => {
+            stapled = true;
+            Some(Arc::new(CertifiedKey {
+                ocsp: Some(bytes.clone()),
+                ..key.as_ref().clone()
+            }))
+        }
+        _ => {
+            stapled = false;
+            current.clone()
+        }
+    });
+    if stapled {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::Other,
+            "OCSP response does not match the current certificate",
+        ))
+    }
 }
@@ -3,7 +3,7 @@ use crate::acme::cache::{
     set_certificate, set_challenge_key,
 };
 use crate::acme::jose::{authorization_hash, jose};
-use crate::domains::ACME_DOMAINS;
+use crate::domains::acme_domains;
 use crate::log::LOG_LEVEL;
 use crate::LogLevel;
 use base64::URL_SAFE_NO_PAD;
@@ -11,6 +11,7 @@ pub use cache::{get_certificate, get_challenge_key};
 use colored::Colorize;
 pub use handler::handle_acme_request;
 use lazy_static::lazy_static;
+pub use ocsp::Ocsp;
 use rcgen::{
     Certificate, CertificateParams, CustomExtension, DistinguishedName, PKCS_ECDSA_P256_SHA256,
 };
@@ -29,12 +30,15 @@ use tokio::time::{interval, sleep, Duration};
 mod cache;
 mod handler;
 mod jose;
+mod ocsp;
 
 lazy_static! {
     pub static ref DIRECTORY_URL: String = var("XDG_ACME_DIRECTORY")
         .unwrap_or("https://acme-staging-v02.api.letsencrypt.org/directory".to_string());
     pub static ref CONTACT: String =
         var("XDG_ACME_CONTACT").unwrap_or("mailto:admin@packurl.net".to_string());
+    pub static ref STATE_DIRECTORY: String =
+        var("XDG_STATE_HOME").unwrap_or("/var/lib/packurl".to_string());
 }
 
 pub struct Account {
@@ -114,10 +118,7 @@ impl Account {
             .json::<Directory>()
             .await
             .map_err(|err| Error::new(ErrorKind::Other, err))?;
-        match self
-            .new_order(&client, &directory, ACME_DOMAINS.into())
-            .await?
-        {
+        match self.new_order(&client, &directory, acme_domains()).await? {
             Order::Invalid => return Err(Error::new(ErrorKind::Other, "Order is invalid")),
             Order::Pending {
                 authorizations,
@@ -346,7 +347,7 @@ impl Account {
 
     async fn finalize(&self, client: &Client, directory: &Directory, url: &str) -> Result<()> {
         LogLevel::Info.log(|| println!("{}", "Creating CSR"));
-        let mut params = CertificateParams::new(ACME_DOMAINS);
+        let mut params = CertificateParams::new(acme_domains());
         params.distinguished_name = DistinguishedName::new();
         params.alg = &PKCS_ECDSA_P256_SHA256;
         let cert =
@@ -0,0 +1,529 @@
+use crate::acme::cache::{
+    backup_ocsp_response, get_certificate, restore_ocsp_response, set_ocsp_response,
+};
+use crate::log::LogLevel;
+use colored::Colorize;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::Client;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA384_ASN1,
+    ECDSA_P384_SHA256_ASN1, ECDSA_P384_SHA384_ASN1, RSA_PKCS1_2048_8192_SHA256,
+    RSA_PKCS1_2048_8192_SHA384, RSA_PKCS1_2048_8192_SHA512,
+};
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::{OID_EC_P256, OID_NIST_EC_P384, OID_PKIX_ACCESS_DESCRIPTOR_OCSP};
+use x509_parser::parse_x509_certificate;
+use x509_parser::time::ASN1Time;
+use x509_parser::x509::SubjectPublicKeyInfo;
+use yasna::models::ObjectIdentifier;
+use yasna::{parse_der, Tag};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3_600);
+const DEFAULT_VALIDITY: u64 = 86_400;
+const SHA1_OID: &[u64] = &[1, 3, 14, 3, 2, 26];
+const BASIC_OCSP_RESPONSE_OID: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 1];
+const ECDSA_SHA256_OID: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const ECDSA_SHA384_OID: &[u64] = &[1, 2, 840, 10045, 4, 3, 3];
+const RSA_SHA256_OID: &[u64] = &[1, 2, 840, 113549, 1, 1, 11];
+const RSA_SHA384_OID: &[u64] = &[1, 2, 840, 113549, 1, 1, 12];
+const RSA_SHA512_OID: &[u64] = &[1, 2, 840, 113549, 1, 1, 13];
+
+pub struct Ocsp {}
+
+impl Ocsp {
+    pub fn init() {
+        tokio::spawn(async move {
+            let mut interval = interval(CHECK_INTERVAL);
+            let mut stapled: Option<(String, u64)> = None;
+            loop {
+                interval.tick().await;
+                match Self::refresh(&stapled).await {
+                    Ok(Some(refreshed)) => {
+                        LogLevel::Info
+                            .log(|| println!("{}", "Successfully stapled OCSP response".green()));
+                        stapled = Some(refreshed);
+                    }
+                    Ok(None) => {}
+                    Err(err) => LogLevel::Warning.log(|| {
+                        println!("{}", "Failed to refresh OCSP response".red());
+                        println!("{:?}", err);
+                    }),
+                }
+            }
+        });
+    }
+
+    async fn refresh(stapled: &Option<(String, u64)>) -> Result<Option<(String, u64)>> {
+        let key = match get_certificate() {
+            Some(key) => key,
+            None => {
+                LogLevel::Debug.log(|| println!("{}", "No certificate to staple OCSP response to"));
+                return Ok(None);
+            }
+        };
+        let chain: Vec<&[u8]> = key.cert.iter().map(|it| it.0.as_slice()).collect();
+        let request = OcspRequest::try_from(chain.as_slice())?;
+        let now = now();
+        if let Some((serial, refresh_at)) = stapled {
+            if serial == &request.serial && *refresh_at > now {
+                return Ok(None);
+            }
+        }
+        if let Some(bytes) = restore_ocsp_response(&request.serial).await {
+            if let Ok(refresh_at) = request.validate(&bytes, now) {
+                if refresh_at > now {
+                    LogLevel::Info.log(|| println!("{}", "Restoring OCSP response"));
+                    set_ocsp_response(chain[0], bytes)?;
+                    return Ok(Some((request.serial, refresh_at)));
+                }
+            }
+        }
+        LogLevel::Info.log(|| {
+            println!(
+                "{}",
+                format!("Requesting OCSP response from {}", request.url.purple())
+            )
+        });
+        let bytes = request.send().await?;
+        let refresh_at = request.validate(&bytes, now)?;
+        backup_ocsp_response(&request.serial, &bytes).await?;
+        set_ocsp_response(chain[0], bytes)?;
+        Ok(Some((request.serial, refresh_at)))
+    }
+}
+
+struct OcspRequest {
+    url: String,
+    serial: String,
+    raw_serial: Vec<u8>,
+    name_hash: Vec<u8>,
+    key_hash: Vec<u8>,
+    /// The issuer certificate, that signs the responses (or delegates to a responder).
+    issuer: Vec<u8>,
+    der: Vec<u8>,
+}
+
+impl TryFrom<&[&[u8]]> for OcspRequest {
+    type Error = Error;
+
+    fn try_from(chain: &[&[u8]]) -> Result<Self> {
+        if chain.len() < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Certificate chain is missing the issuer",
+            ));
+        }
+        let (_, leaf) = parse_x509_certificate(chain[0])
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let (_, issuer) = parse_x509_certificate(chain[1])
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let url = leaf
+            .extensions()
+            .iter()
+            .find_map(|it| match it.parsed_extension() {
+                ParsedExtension::AuthorityInfoAccess(aia) => aia
+                    .iter()
+                    .filter(|it| it.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+                    .find_map(|it| match it.access_location {
+                        GeneralName::URI(uri) => Some(uri.to_string()),
+                        _ => None,
+                    }),
+                _ => None,
+            })
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing OCSP responder url"))?;
+        let raw_serial = trim_leading_zeros(leaf.raw_serial()).to_vec();
+        let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, leaf.issuer().as_raw());
+        let key_hash = digest(
+            &SHA1_FOR_LEGACY_USE_ONLY,
+            issuer.public_key().subject_public_key.data.as_ref(),
+        );
+        let der = yasna::construct_der(|writer| {
+            writer.write_sequence(|writer| {
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_sequence(|writer| {
+                        writer.next().write_sequence(|writer| {
+                            writer.next().write_sequence(|writer| {
+                                write_cert_id(
+                                    writer,
+                                    name_hash.as_ref(),
+                                    key_hash.as_ref(),
+                                    &raw_serial,
+                                );
+                            });
+                        });
+                    });
+                });
+            });
+        });
+        Ok(OcspRequest {
+            url,
+            serial: raw_serial.iter().map(|it| format!("{:02x}", it)).collect(),
+            raw_serial,
+            name_hash: name_hash.as_ref().to_vec(),
+            key_hash: key_hash.as_ref().to_vec(),
+            issuer: chain[1].to_vec(),
+            der,
+        })
+    }
+}
+
+impl OcspRequest {
+    async fn send(&self) -> Result<Vec<u8>> {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/ocsp-request"),
+        );
+        headers.append(
+            ACCEPT,
+            HeaderValue::from_static("application/ocsp-response"),
+        );
+        let response = Client::new()
+            .post(self.url.as_str())
+            .headers(headers)
+            .body(self.der.clone())
+            .send()
+            .await
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+        if response.status().is_success() {
+            Ok(response
+                .bytes()
+                .await
+                .map_err(|err| Error::new(ErrorKind::Other, err))?
+                .to_vec())
+        } else {
+            Err(Error::new(
+                ErrorKind::Other,
+                format!("OCSP responder returned {}", response.status()),
+            ))
+        }
+    }
+
+    /// Checks that the response is a successful "good" status for our certificate, signed by its
+    /// issuer (or a responder it delegated to), and returns the time (in seconds since the epoch)
+    /// after which it should be refreshed.
+    fn validate(&self, bytes: &[u8], now: u64) -> Result<u64> {
+        let basic = parse_der(bytes, |reader| {
+            reader.read_sequence(|reader| {
+                let status = reader.next().read_enum()?;
+                let basic = reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(0), |reader| {
+                        reader.read_sequence(|reader| {
+                            let oid = reader.next().read_oid()?;
+                            let response = reader.next().read_bytes()?;
+                            Ok((oid, response))
+                        })
+                    })
+                })?;
+                Ok((status, basic))
+            })
+        })
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let response = match basic {
+            (0, Some((oid, response)))
+                if oid == ObjectIdentifier::from_slice(BASIC_OCSP_RESPONSE_OID) =>
+            {
+                response
+            }
+            (status, _) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("OCSP response status is {}", status),
+                ))
+            }
+        };
+        let (tbs, algorithm, signature, certs) = parse_der(&response, |reader| {
+            reader.read_sequence(|reader| {
+                let tbs = reader.next().read_der()?;
+                let algorithm = reader.next().read_sequence(|reader| {
+                    let oid = reader.next().read_oid()?;
+                    reader.read_optional(|reader| reader.read_der())?;
+                    Ok(oid)
+                })?;
+                let (signature, _) = reader.next().read_bitvec_bytes()?;
+                let certs = reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(0), |reader| {
+                        reader.collect_sequence_of(|reader| reader.read_der())
+                    })
+                })?;
+                Ok((tbs, algorithm, signature, certs.unwrap_or_default()))
+            })
+        })
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        // the response comes over plain http, so only its signature makes it trustworthy
+        self.verify_signer(&tbs, algorithm.components(), &signature, &certs, now)?;
+        let single_responses = parse_der(&tbs, |reader| {
+            reader.read_sequence(|reader| {
+                reader.read_optional(|reader| {
+                    reader.read_tagged(Tag::context(0), |reader| reader.read_u8())
+                })?;
+                reader.next().read_tagged_der()?;
+                reader.next().read_generalized_time()?;
+                let mut responses = Vec::new();
+                reader.next().read_sequence_of(|reader| {
+                    responses.push(reader.read_sequence(|reader| {
+                        let cert_id = reader.next().read_sequence(|reader| {
+                            let algorithm = reader.next().read_sequence(|reader| {
+                                let oid = reader.next().read_oid()?;
+                                reader.read_optional(|reader| reader.read_null())?;
+                                Ok(oid)
+                            })?;
+                            let name_hash = reader.next().read_bytes()?;
+                            let key_hash = reader.next().read_bytes()?;
+                            let serial = reader.next().read_bigint_bytes()?;
+                            Ok((algorithm, name_hash, key_hash, serial.0))
+                        })?;
+                        let status = reader.next().read_tagged_der()?;
+                        let this_update = reader.next().read_generalized_time()?;
+                        let next_update = reader.read_optional(|reader| {
+                            reader.read_tagged(Tag::context(0), |reader| {
+                                reader.read_generalized_time()
+                            })
+                        })?;
+                        reader.read_optional(|reader| reader.read_tagged_der())?;
+                        Ok((
+                            cert_id,
+                            status.tag(),
+                            this_update.datetime().unix_timestamp(),
+                            next_update.map(|it| it.datetime().unix_timestamp()),
+                        ))
+                    })?);
+                    Ok(())
+                })?;
+                reader.read_optional(|reader| reader.read_tagged_der())?;
+                Ok(responses)
+            })
+        })
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let (_, status, this_update, next_update) = single_responses
+            .into_iter()
+            .find(|((algorithm, name_hash, key_hash, serial), _, _, _)| {
+                algorithm.components().as_slice() == SHA1_OID
+                    && *name_hash == self.name_hash
+                    && *key_hash == self.key_hash
+                    && trim_leading_zeros(serial) == self.raw_serial.as_slice()
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "OCSP response does not cover the certificate",
+                )
+            })?;
+        if status != Tag::context(0) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "OCSP response status is not \"good\"",
+            ));
+        }
+        let this_update = this_update.max(0) as u64;
+        let next_update = next_update
+            .map(|it| it.max(0) as u64)
+            .unwrap_or(this_update + DEFAULT_VALIDITY);
+        if next_update <= now {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "OCSP response has expired",
+            ));
+        }
+        // refresh half-way through the validity period
+        Ok(this_update + next_update.saturating_sub(this_update) / 2)
+    }
+
+    // Checks that the response is signed by the issuer, or by a responder certificate that the
+    // issuer signed for OCSP signing (RFC 6960, section 4.2.2.2), included in the response.
+    fn verify_signer(
+        &self,
+        tbs: &[u8],
+        algorithm: &[u64],
+        signature: &[u8],
+        certs: &[Vec<u8>],
+        now: u64,
+    ) -> Result<()> {
+        let (_, issuer) = parse_x509_certificate(&self.issuer)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        if verify(algorithm, issuer.public_key(), tbs, signature) {
+            return Ok(());
+        }
+        let now = ASN1Time::from_timestamp(now as i64)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        for cert in certs {
+            let responder = match parse_x509_certificate(cert) {
+                Ok((_, it)) => it,
+                Err(_) => continue,
+            };
+            let delegated = responder.validity().is_valid_at(now)
+                && responder
+                    .extended_key_usage()
+                    .ok()
+                    .flatten()
+                    .map_or(false, |it| it.value.ocsp_signing)
+                && responder
+                    .signature_algorithm
+                    .algorithm
+                    .iter()
+                    .map_or(false, |oid| {
+                        verify(
+                            &oid.collect::<Vec<u64>>(),
+                            issuer.public_key(),
+                            responder.tbs_certificate.as_ref(),
+                            &responder.signature_value.data,
+                        )
+                    });
+            if delegated && verify(algorithm, responder.public_key(), tbs, signature) {
+                return Ok(());
+            }
+        }
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "OCSP response is not signed by the issuer",
+        ))
+    }
+}
+
+fn verify(algorithm: &[u64], key: &SubjectPublicKeyInfo, message: &[u8], signature: &[u8]) -> bool {
+    let curve = key
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|it| it.as_oid().ok());
+    let (p256, p384) = (curve == Some(OID_EC_P256), curve == Some(OID_NIST_EC_P384));
+    let algorithm: &dyn VerificationAlgorithm = match algorithm {
+        ECDSA_SHA256_OID if p256 => &ECDSA_P256_SHA256_ASN1,
+        ECDSA_SHA256_OID if p384 => &ECDSA_P384_SHA256_ASN1,
+        ECDSA_SHA384_OID if p256 => &ECDSA_P256_SHA384_ASN1,
+        ECDSA_SHA384_OID if p384 => &ECDSA_P384_SHA384_ASN1,
+        RSA_SHA256_OID => &RSA_PKCS1_2048_8192_SHA256,
+        RSA_SHA384_OID => &RSA_PKCS1_2048_8192_SHA384,
+        RSA_SHA512_OID => &RSA_PKCS1_2048_8192_SHA512,
+        _ => return false,
+    };
+    UnparsedPublicKey::new(algorithm, &key.subject_public_key.data)
+        .verify(message, signature)
+        .is_ok()
+}
+
+fn write_cert_id(
+    writer: &mut yasna::DERWriterSeq,
+    name_hash: &[u8],
+    key_hash: &[u8],
+    serial: &[u8],
+) {
+    writer.next().write_sequence(|writer| {
+        writer
+            .next()
+            .write_oid(&ObjectIdentifier::from_slice(SHA1_OID));
+        writer.next().write_null();
+    });
+    writer.next().write_bytes(name_hash);
+    writer.next().write_bytes(key_hash);
+    writer.next().write_bigint_bytes(serial, true);
+}
+
+fn trim_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|it| *it != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acme::ocsp::OcspRequest;
+
+    // Fixtures generated with openssl: a leaf with serial 0x0123456789, its issuer,
+    // the request from `openssl ocsp -no_nonce -reqout` and the responder's answers,
+    // produced at THIS_UPDATE and valid for 7 days.
+    const CA: &[u8] = include_bytes!("testdata/ocsp-ca.der");
+    const LEAF: &[u8] = include_bytes!("testdata/ocsp-leaf.der");
+    const REQUEST: &[u8] = include_bytes!("testdata/ocsp-request.der");
+    const GOOD: &[u8] = include_bytes!("testdata/ocsp-good.der");
+    const REVOKED: &[u8] = include_bytes!("testdata/ocsp-revoked.der");
+    // signed by a responder certified by the CA for OCSP signing
+    const DELEGATED: &[u8] = include_bytes!("testdata/ocsp-delegated.der");
+    // signed by a self-signed certificate with the name of the CA
+    const FORGED: &[u8] = include_bytes!("testdata/ocsp-forged.der");
+    // signed by a certificate of the CA that isn't for OCSP signing
+    const UNAUTHORIZED: &[u8] = include_bytes!("testdata/ocsp-unauthorized.der");
+    const THIS_UPDATE: u64 = 1_792_368_987;
+    const NEXT_UPDATE: u64 = THIS_UPDATE + 7 * 86_400;
+
+    fn request() -> OcspRequest {
+        OcspRequest::try_from([LEAF, CA].as_slice()).unwrap()
+    }
+
+    #[test]
+    fn request_der() {
+        let request = request();
+        assert_eq!(request.url, "http://ocsp.example.test");
+        assert_eq!(request.serial, "0123456789");
+        assert_eq!(request.der, REQUEST);
+        assert!(OcspRequest::try_from([LEAF].as_slice()).is_err());
+    }
+
+    #[test]
+    fn good_response() {
+        let refresh_at = request().validate(GOOD, THIS_UPDATE + 60).unwrap();
+        assert_eq!(refresh_at, THIS_UPDATE + (NEXT_UPDATE - THIS_UPDATE) / 2);
+        assert!(request().validate(GOOD, NEXT_UPDATE).is_err());
+    }
+
+    #[test]
+    fn signatures() {
+        assert!(request().validate(DELEGATED, THIS_UPDATE + 3_600).is_ok());
+        assert!(request().validate(FORGED, THIS_UPDATE + 3_600).is_err());
+        assert!(request()
+            .validate(UNAUTHORIZED, THIS_UPDATE + 3_600)
+            .is_err());
+        // a bit flipped in the signature (the first bit string, before the certificates)
+        let mut tampered = GOOD.to_vec();
+        let at = tampered
+            .windows(3)
+            .position(|it| it == [0x03, 0x49, 0x00])
+            .unwrap();
+        tampered[at + 10] ^= 1;
+        assert!(request().validate(&tampered, THIS_UPDATE + 60).is_err());
+        // a next update pushed back by 3 years
+        let mut tampered = GOOD.to_vec();
+        let at = tampered
+            .windows(8)
+            .position(|it| it == b"20261026")
+            .unwrap();
+        tampered[at + 3] = b'9';
+        assert!(request().validate(&tampered, THIS_UPDATE + 60).is_err());
+    }
+
+    #[test]
+    fn revoked_response() {
+        assert!(request().validate(REVOKED, THIS_UPDATE + 60).is_err());
+    }
+
+    #[test]
+    fn uncovered_response() {
+        let mut request = self::request();
+        request.raw_serial = vec![0x98, 0x76, 0x54, 0x32, 0x10];
+        assert!(request.validate(GOOD, THIS_UPDATE + 60).is_err());
+        let mut request = self::request();
+        request.key_hash[0] ^= 1;
+        assert!(request.validate(GOOD, THIS_UPDATE + 60).is_err());
+    }
+
+    #[test]
+    fn malformed_response() {
+        assert!(request()
+            .validate(&GOOD[..GOOD.len() / 2], THIS_UPDATE)
+            .is_err());
+        // unauthorized (6), without a response body
+        assert!(request()
+            .validate(&[0x30, 0x03, 0x0a, 0x01, 0x06], THIS_UPDATE)
+            .is_err());
+    }
+}
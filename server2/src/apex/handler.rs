@@ -1,7 +1,19 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::apex::well_known::WellKnown;
+use crate::deploy::handle_deploy_request;
+use crate::http::{read_request, Limits, Method, RequestError};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 
+// Only a few short paths exist, and the app urls can be very long since they hold the data:
+// those are refused without reading the rest of the request.
+// The configured .well-known documents all have short paths as well.
+const LIMITS: Limits = Limits {
+    request_line: 256,
+    head: 8192,
+    headers: 64,
+};
+
 const METHOD_NOT_ALLOWED_RESPONSE: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\n\
 Cache-Control: no-cache\r\n\
 Allow: GET\r\n\
@@ -78,28 +90,42 @@ pub async fn handle_redirect_to_https(stream: &mut TcpStream) {
 }
 
 pub async fn handle_apex_request(stream: &mut TlsStream<TcpStream>) {
-    let mut buf = [0; 16];
-    if stream.read_exact(&mut buf).await.is_ok() {
-        match &buf {
-            b"GET / HTTP/1.1\r\n" => {
-                let _ = stream.write_all(ROOT_REDIRECT_RESPONSE).await;
-            }
-            b"GET /sw.mjs HTTP" => {
-                let _ = stream.write_all(SERVICE_WORKER_RESPONSE).await;
-            }
-            b"GET /pwa.json HT" => {
-                let _ = stream.write_all(MANIFEST_RESPONSE).await;
-            }
-            b"GET /favicon.ico" => {
-                let _ = stream.write_all(FAVICON_RESPONSE).await;
-            }
-            _ => {
-                if buf.starts_with(b"GET ") {
-                    let _ = stream.write_all(CONTENT_TOO_LARGE_RESPONSE).await;
-                } else {
-                    let _ = stream.write_all(METHOD_NOT_ALLOWED_RESPONSE).await;
-                }
+    let request = match read_request(stream, &LIMITS).await {
+        Ok(it) => it,
+        Err(RequestError::UriTooLong) => {
+            let _ = stream.write_all(CONTENT_TOO_LARGE_RESPONSE).await;
+            return;
+        }
+        Err(err) => {
+            if let Some(response) = err.response() {
+                let _ = stream.write_all(&response).await;
             }
+            return;
+        }
+    };
+    match (&request.0.method, request.0.target.as_str()) {
+        (Method::Get, "/") => {
+            let _ = stream.write_all(ROOT_REDIRECT_RESPONSE).await;
+        }
+        (Method::Get, "/sw.mjs") => {
+            let _ = stream.write_all(SERVICE_WORKER_RESPONSE).await;
+        }
+        (Method::Get, "/pwa.json") => {
+            let _ = stream.write_all(MANIFEST_RESPONSE).await;
+        }
+        (Method::Get, "/favicon.ico") => {
+            let _ = stream.write_all(FAVICON_RESPONSE).await;
+        }
+        (Method::Post, "/deploy") => {
+            let (request, body_start) = request;
+            handle_deploy_request(stream, &request, body_start).await;
+        }
+        (Method::Get, target) => {
+            let response = WellKnown::response(target).unwrap_or(CONTENT_TOO_LARGE_RESPONSE);
+            let _ = stream.write_all(response).await;
+        }
+        _ => {
+            let _ = stream.write_all(METHOD_NOT_ALLOWED_RESPONSE).await;
         }
     }
 }
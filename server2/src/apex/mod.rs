@@ -1,3 +1,5 @@
 pub use handler::{handle_apex_request, handle_redirect_to_https};
+pub use well_known::WellKnown;
 
 mod handler;
+mod well_known;
@@ -0,0 +1,131 @@
+use crate::log::LogLevel;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::env::var;
+use std::fs::read;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// The documents that can be served under `/.well-known/`, with their content type.
+const DOCUMENTS: [(&str, &str); 3] = [
+    ("security.txt", "text/plain; charset=utf-8"),
+    ("assetlinks.json", "application/json"),
+    ("apple-app-site-association", "application/json"),
+];
+const CHANGE_PASSWORD: &str = "change-password";
+
+lazy_static! {
+    static ref DIR: Option<String> = var("XDG_WELL_KNOWN_DIR").ok();
+    static ref CHANGE_PASSWORD_URL: Option<String> = var("XDG_WELL_KNOWN_CHANGE_PASSWORD").ok();
+    static ref RESPONSES: std::result::Result<HashMap<String, Vec<u8>>, String> =
+        load_responses().map_err(|err| err.to_string());
+}
+
+pub struct WellKnown {}
+
+impl WellKnown {
+    /// Loads the documents, so that errors are reported at startup.
+    pub fn init() -> Result<()> {
+        match RESPONSES.as_ref() {
+            Ok(responses) => {
+                for path in responses.keys() {
+                    LogLevel::Info.log(|| println!("Serving {}", path.yellow()));
+                }
+                Ok(())
+            }
+            Err(err) => Err(Error::new(ErrorKind::InvalidInput, err.clone())),
+        }
+    }
+
+    /// Returns the response for the request target, if it is one of the configured documents.
+    pub fn response(target: &str) -> Option<&'static [u8]> {
+        RESPONSES.as_ref().ok()?.get(target).map(|it| it.as_slice())
+    }
+}
+
+fn load_responses() -> Result<HashMap<String, Vec<u8>>> {
+    let mut responses = HashMap::new();
+    if let Some(dir) = DIR.as_ref() {
+        for (name, content_type) in DOCUMENTS {
+            let body = match read(Path::new(dir).join(name)) {
+                Ok(it) => it,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            if content_type == "application/json"
+                && serde_json::from_slice::<serde_json::Value>(&body).is_err()
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid json in {}/{}", dir, name),
+                ));
+            }
+            responses.insert(
+                format!("/.well-known/{}", name),
+                document_response(content_type, &body),
+            );
+        }
+    }
+    if let Some(url) = CHANGE_PASSWORD_URL.as_ref() {
+        if (!url.starts_with("https://") && !url.starts_with('/')) || url.contains(['\r', '\n']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid change password url: {}", url),
+            ));
+        }
+        responses.insert(
+            format!("/.well-known/{}", CHANGE_PASSWORD),
+            redirect_response(url),
+        );
+    }
+    Ok(responses)
+}
+
+fn document_response(content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Content-Type: {}\r\n\
+Content-Length: {}\r\n\
+X-Content-Type-Options: nosniff\r\n\
+Strict-Transport-Security: max-age=63072000; includeSubDomains; preload\r\n\
+\r\n",
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+// The change password url is a temporary redirect, as it is a pointer to a page that can move.
+fn redirect_response(url: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 302 Found\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Location: {}\r\n\
+Content-Length: 0\r\n\
+Strict-Transport-Security: max-age=63072000; includeSubDomains; preload\r\n\
+\r\n",
+        url
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apex::well_known::document_response;
+
+    #[test]
+    fn document_headers() {
+        let response = document_response("application/json", b"[]");
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(response.contains("\r\nContent-Length: 2\r\n"));
+        assert!(response.ends_with("\r\n\r\n[]"));
+    }
+}
@@ -1,37 +1,47 @@
+use crate::cdn::compress::{compress, compress_file, is_compressible};
+use crate::cdn::encoding::Encoding;
+use crate::cdn::hashed::content_hash;
+use crate::cdn::integrity::sri;
+use crate::cdn::memory::{count_streamed, enforce_budget, Bodies, Resident, STREAM_THRESHOLD};
+use crate::cdn::mime::MimeType;
 use crate::cdn::path::UriPath;
-use crate::domains::CDN;
+use crate::cdn::policy::Policy;
+use crate::cdn::site::Site;
+use crate::cdn::visit::{is_hidden, visit, Visit};
+use crate::cdn::watch::watch;
 use crate::log::LogLevel;
 use async_recursion::async_recursion;
 use colored::Colorize;
-use dashmap::DashMap;
-use lazy_static::lazy_static;
-use std::env::var;
-use std::io::{Error, ErrorKind, Result};
-use std::path::Path;
+use httpdate::fmt_http_date;
+use ring::digest::{digest, Context, Digest, SHA256, SHA384};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::{Error, ErrorKind, Result, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::UNIX_EPOCH;
-use tokio::fs::{metadata, read, read_dir};
-use tokio::sync::Mutex;
-
-lazy_static! {
-    pub static ref ROOT: String = var("XDG_WWW_ROOT").unwrap_or("/var/www".to_string());
-    pub static ref PREFIX: String = var("XDG_WWW_PREFIX").unwrap_or("/".to_string());
-    pub static ref FILES: DashMap<String, FileEntry> = DashMap::with_capacity(1024);
-    pub static ref CDN_ROOT: String = format!("https://{}", CDN);
-    static ref LOCK: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
-}
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{metadata, read, read_dir, File};
+use tokio::io::{copy, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+pub type Files = HashMap<String, Arc<FileEntry>>;
 
 pub struct Cache {}
 
 impl Cache {
-    pub fn init() {
-        LogLevel::Info.log(|| {
-            println!(
-                "Using web root {} with prefix {}",
-                ROOT.yellow(),
-                PREFIX.yellow()
-            )
-        });
+    pub fn init() -> Result<()> {
+        Site::validate()?;
+        MimeType::validate()?;
+        for site in Site::all() {
+            LogLevel::Info.log(|| {
+                println!(
+                    "Using web root {} with prefix {} for {}",
+                    site.root.yellow(),
+                    site.prefix.yellow(),
+                    site.host.yellow()
+                )
+            });
+        }
         tokio::spawn(async move {
             match Self::update().await {
                 Ok(_) => {
@@ -44,268 +54,912 @@ impl Cache {
                 }),
             }
         });
+        for site in Site::all() {
+            watch(site);
+        }
+        Ok(())
     }
 
-    pub async fn update() -> Result<()> {
-        let lock = match LOCK.try_lock() {
+    /// Rebuilds the cache of every site from its web root.
+    pub async fn update() -> Result<Summary> {
+        let mut summary = Summary::default();
+        for site in Site::all() {
+            summary.merge(Self::update_site(site).await?);
+        }
+        Ok(summary)
+    }
+
+    /// Rebuilds the cache of the site from its web root.
+    /// The new snapshot replaces the current one only once it is complete.
+    pub async fn update_site(site: &'static Site) -> Result<Summary> {
+        let lock = match site.lock.try_lock() {
             Ok(lock) => lock,
             Err(_) => {
                 LogLevel::Info
                     .log(|| println!("{}", "Waiting for previous update to finish".yellow()));
-                LOCK.lock().await
+                site.lock.lock().await
             }
         };
-        LogLevel::Info.log(|| println!("{}", "Updating file cache".purple()));
-        let path = Path::new(ROOT.as_str());
-        let mut removed = Vec::new();
-        for entry in FILES.iter() {
-            let key = entry.key();
-            println!("path: {:?}", path.join(&key[PREFIX.len()..]));
-            match metadata(path.join(&key[PREFIX.len()..])).await {
-                Ok(stat) => {
-                    if stat.is_dir() {
-                        match metadata(path.join("index.html")).await {
-                            Ok(stat) => {
-                                if !stat.is_file() {
-                                    removed.push(key.to_string());
-                                }
-                            }
-                            Err(_) => {
-                                removed.push(key.to_string());
-                            }
-                        }
-                    } else if !stat.is_file() {
-                        removed.push(key.to_string());
-                    }
+        LogLevel::Info.log(|| println!("{} {}", "Updating file cache for".purple(), site.host));
+        let mut snapshot = Snapshot::new(site, false);
+        walk(Path::new(site.root.as_str()), &mut snapshot).await?;
+        let summary = snapshot.swap();
+        drop(lock);
+        Ok(summary)
+    }
+
+    /// Updates the entries for the given paths only (and everything under them for directories).
+    /// The whole cache is rebuilt when the web root itself changed (e.g. a swapped symlink).
+    pub async fn update_paths(site: &'static Site, paths: Vec<PathBuf>) -> Result<Summary> {
+        let root = Path::new(site.root.as_str());
+        if paths.iter().any(|it| it == root) {
+            return Self::update_site(site).await;
+        }
+        let lock = site.lock.lock().await;
+        let mut paths: Vec<PathBuf> = paths
+            .into_iter()
+            .filter(|it| {
+                it.strip_prefix(root).map_or(false, |it| {
+                    !it.iter().any(|it| it.to_str().map_or(true, is_hidden))
+                })
+            })
+            .map(|it| {
+                match it
+                    .to_str()
+                    .and_then(|it| it.strip_suffix(".br").or_else(|| it.strip_suffix(".gz")))
+                {
+                    Some(uncompressed) => PathBuf::from(uncompressed),
+                    None => it,
                 }
-                Err(_) => removed.push(key.to_string()),
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+        let mut snapshot = Snapshot::new(site, true);
+        let mut previous: Option<PathBuf> = None;
+        for path in paths {
+            if let Some(previous) = previous.as_ref() {
+                if path.starts_with(previous) {
+                    continue;
+                }
+            }
+            // entries that are still there are found again by the walk
+            remove(site, &path, &mut snapshot.files);
+            if metadata(&path).await.is_ok() {
+                walk(&path, &mut snapshot).await?;
             }
+            previous = Some(path);
         }
-        removed.into_iter().for_each(|it| {
-            LogLevel::Debug.log(|| println!("{}", format!("Removing {}", it.red())));
-            let _ = FILES.remove(&it);
-        });
-        walk(path).await?;
+        let summary = snapshot.swap();
         drop(lock);
-        Ok(())
+        Ok(summary)
     }
 }
 
-pub struct FileEntry {
-    pub etag: String,
-    pub ok: Vec<u8>,
-    pub not_modified: Vec<u8>,
+/// The changes made to the cache by an update, as host and key.
+#[derive(Default)]
+pub struct Summary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    /// Paths of the web root left out of the cache, with the reason.
+    pub skipped: Vec<String>,
 }
 
-async fn etag(path: &Path) -> Result<String> {
-    let meta = metadata(path).await?;
-    let content_length = meta.len();
-    let last_modified = meta
-        .modified()?
-        .duration_since(UNIX_EPOCH)
-        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?
-        .as_secs();
-    Ok(format!("{:#x}{:#x}", content_length, last_modified))
+impl Summary {
+    fn merge(&mut self, other: Summary) {
+        self.added.extend(other.added);
+        self.updated.extend(other.updated);
+        self.removed.extend(other.removed);
+        self.skipped.extend(other.skipped);
+    }
 }
 
-fn cache_control_and_content_type(filename: &String) -> Option<(&str, &str)> {
-    if filename.ends_with(".html") || filename.ends_with(".htm") {
-        Some(("public,no-cache", "text/html"))
-    } else if filename.ends_with(".css") {
-        Some(("public,no-cache", "text/css"))
-    } else if filename.ends_with(".js")
-        || filename.ends_with(".mjs")
-        || filename.ends_with(".js.map")
-        || filename.ends_with(".mjs.map")
-    {
-        Some(("public,no-cache", "application/javascript"))
-    } else if filename.ends_with(".svg") {
-        Some(("public,max-age=31536000,immutable", "image/svg+xml"))
-    } else if filename.ends_with(".jpg") {
-        Some(("public,max-age=31536000,immutable", "image/jpeg"))
-    } else if filename.ends_with(".png") {
-        Some(("public,max-age=31536000,immutable", "image/png"))
-    } else if filename.ends_with(".webp") {
-        Some(("public,max-age=31536000,immutable", "image/webp"))
-    } else if filename.ends_with(".ico") {
-        Some(("public,max-age=31536000,immutable", "image/x-icon"))
-    } else if filename.ends_with(".woff2") {
-        Some(("public,max-age=31536000,immutable", "font/woff2"))
-    } else if filename.ends_with(".json") {
-        Some(("public,max-age=3600,must-revalidate", "application/json"))
-    } else if filename.ends_with(".wasm") {
-        Some(("public,max-age=31536000,immutable", "application/wasm"))
-    } else if filename.ends_with(".glb") {
-        Some(("public,max-age=31536000,immutable", "model/gltf-binary"))
-    } else if filename.ends_with(".md") {
-        Some(("public,no-cache", "text/markdown"))
-    } else if filename.ends_with(".xml") {
-        Some(("public,max-age=3600,must-revalidate", "application/xml"))
-    } else if filename.ends_with(".txt")
-        || filename.ends_with(".glsl")
-        || filename.ends_with(".wat")
-    {
-        Some(("public,no-cache", "text/plain"))
-    } else if filename.ends_with(".mp3") {
-        Some(("public,max-age=31536000,immutable", "audio/mp3"))
-    } else if filename.ends_with(".mp4") {
-        Some(("public,max-age=31536000,immutable", "audio/mp4"))
-    } else if filename.ends_with(".sig") {
-        Some(("no-store", "application/pgp-signature"))
-    } else {
-        None
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "added: {}, updated: {}, removed: {}, skipped: {}",
+            self.added.len(),
+            self.updated.len(),
+            self.removed.len(),
+            self.skipped.len()
+        )?;
+        for it in &self.added {
+            writeln!(f, "+ {}", it)?;
+        }
+        for it in &self.updated {
+            writeln!(f, "~ {}", it)?;
+        }
+        for it in &self.removed {
+            writeln!(f, "- {}", it)?;
+        }
+        for it in &self.skipped {
+            writeln!(f, "! {}", it)?;
+        }
+        Ok(())
     }
 }
 
-async fn build_response(
-    path: &Path,
-    etag: String,
-    cache_control: &str,
-    content_type: &str,
-) -> Result<FileEntry> {
-    if let Some(filename) = path.file_name().and_then(|it| it.to_str()) {
-        let (data, compressed) = if let Some(compressed_path) =
-            path.parent().map(|it| it.join(format!("{}.br", filename)))
-        {
-            match read(compressed_path).await {
-                Ok(data) => (data, "Content-Encoding: br\r\n"),
-                Err(_) => (read(path).await?, ""),
+/// The next version of the cache, built aside while the current one is being served.
+/// Unchanged entries are shared with the current version.
+struct Snapshot {
+    site: &'static Site,
+    current: Arc<Files>,
+    files: Files,
+    summary: Summary,
+    /// The canonical web root, that symbolic links must not escape.
+    root: PathBuf,
+    /// The canonical paths of the directories being walked.
+    ancestors: Vec<PathBuf>,
+}
+
+impl Snapshot {
+    // Starts either empty (for a full rebuild) or from the current entries (for partial updates).
+    fn new(site: &'static Site, from_current: bool) -> Self {
+        let current = site.files.load_full();
+        let files = if from_current {
+            current.as_ref().clone()
+        } else {
+            HashMap::with_capacity(current.len())
+        };
+        let root = Path::new(site.root.as_str());
+        Snapshot {
+            site,
+            current,
+            files,
+            summary: Summary::default(),
+            root: root.canonicalize().unwrap_or(root.to_path_buf()),
+            ancestors: Vec::new(),
+        }
+    }
+
+    async fn add(&mut self, path: &Path, key: String, mime: &MimeType) -> Result<()> {
+        let stamp = match stamp(path).await {
+            Ok(it) => it,
+            Err(_) => return Ok(()),
+        };
+        let entry = match self.current.get(&key) {
+            Some(stored) if stored.stamp == stamp => stored.clone(),
+            stored => {
+                let name = format!("{}{}", self.site.host, key);
+                if stored.is_some() {
+                    LogLevel::Debug.log(|| println!("{}", format!("Updating {}", name.yellow())));
+                    self.summary.updated.push(name);
+                } else {
+                    LogLevel::Debug.log(|| println!("{}", format!("Adding   {}", name.green())));
+                    self.summary.added.push(name);
+                }
+                let policy = self.site.rules.policy(&key);
+                Arc::new(
+                    build_response(path, policy, stamp, &mime.cache_control, &mime.content_type)
+                        .await?,
+                )
             }
+        };
+        self.files.insert(key, entry);
+        Ok(())
+    }
+
+    fn skip(&mut self, path: &Path, reason: &str) {
+        let name = format!("{} ({})", path.display(), reason);
+        LogLevel::Debug.log(|| println!("{}", format!("Skipping {}", name.yellow())));
+        self.summary.skipped.push(name);
+    }
+
+    // Replaces the current version of the cache.
+    fn swap(self) -> Summary {
+        let Snapshot {
+            site,
+            current,
+            files,
+            mut summary,
+            ..
+        } = self;
+        summary.removed = current
+            .keys()
+            .filter(|it| !files.contains_key(it.as_str()))
+            .map(|it| format!("{}{}", site.host, it))
+            .collect();
+        summary.added.sort();
+        summary.updated.sort();
+        summary.removed.sort();
+        summary.skipped.sort();
+        for it in &summary.removed {
+            LogLevel::Debug.log(|| println!("{}", format!("Removing {}", it.red())));
+        }
+        site.files.store(Arc::new(files));
+        // the bodies of the replaced entries are released with the previous version
+        drop(current);
+        enforce_budget();
+        summary
+    }
+}
+
+fn remove(site: &Site, path: &Path, files: &mut Files) {
+    if let Some(uri_path) = UriPath::from(site.prefix.as_str(), site.root.as_str(), path) {
+        let key = uri_path.to_string();
+        let dir = if key.ends_with('/') {
+            key.clone()
         } else {
-            (read(path).await?, "")
+            format!("{}/", key)
         };
-        Ok(FileEntry {
-            ok: [
+        files.retain(|it, _| *it != key && !it.starts_with(&dir));
+        if let (Some(parent), Some(filename)) = (
+            uri_path.parent(),
+            path.file_name().and_then(|it| it.to_str()),
+        ) {
+            if filename == "index.html" {
+                files.remove(&parent.into_dir().to_string());
+            } else {
+                files.remove(&parent.join(filename).to_string());
+            }
+        }
+    }
+}
+
+/// File size and modification time, used to detect changes without reading the file.
+/// Those of the precompressed siblings are included, as they are served for the file.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: SystemTime,
+    br: Option<(u64, SystemTime)>,
+    gzip: Option<(u64, SystemTime)>,
+}
+
+pub struct FileEntry {
+    /// The entity tag of the identity body.
+    etag: String,
+    pub last_modified: SystemTime,
+    stamp: Stamp,
+    cache_control: String,
+    content_type: String,
+    path: PathBuf,
+    identity_len: u64,
+    storage: Storage,
+    /// The prefix of the content digest used in the hashed path of the file.
+    pub hash: String,
+    /// The Subresource Integrity value (sha384) of the identity body.
+    pub integrity: String,
+    pub policy: Policy,
+}
+
+enum Storage {
+    /// Small files are kept in memory, with the encodings that were available when loaded.
+    Memory {
+        resident: Resident,
+        encodings: Vec<Encoding>,
+    },
+    /// Large files are streamed from disk, with their precompressed siblings
+    /// (or their compressed versions from the compression cache) if any.
+    Disk {
+        br: Option<(PathBuf, u64)>,
+        gzip: Option<(PathBuf, u64)>,
+    },
+}
+
+// Where the bytes of a response body are read from.
+enum Source {
+    Memory(Arc<Bodies>, Encoding),
+    Disk(File),
+}
+
+/// A response body ready to be written, with the encoding that is actually used.
+pub struct Body {
+    source: Source,
+    encoding: Encoding,
+    len: u64,
+}
+
+impl Source {
+    async fn write_range<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        range: Range<u64>,
+    ) -> Result<()> {
+        match self {
+            Source::Memory(bodies, encoding) => {
+                let data = match encoding {
+                    Encoding::Brotli => bodies.br.as_ref().unwrap_or(&bodies.identity),
+                    Encoding::Gzip => bodies.gzip.as_ref().unwrap_or(&bodies.identity),
+                    Encoding::Identity => &bodies.identity,
+                };
+                stream
+                    .write_all(&data[range.start as usize..range.end as usize])
+                    .await
+            }
+            Source::Disk(file) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                let mut limited = file.take(range.end - range.start);
+                copy(&mut limited, stream).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FileEntry {
+    pub fn encodings(&self) -> Vec<Encoding> {
+        match &self.storage {
+            Storage::Memory { encodings, .. } => encodings.clone(),
+            Storage::Disk { br, gzip } => {
+                let mut encodings = vec![Encoding::Identity];
+                if br.is_some() {
+                    encodings.push(Encoding::Brotli);
+                }
+                if gzip.is_some() {
+                    encodings.push(Encoding::Gzip);
+                }
+                encodings
+            }
+        }
+    }
+
+    /// Returns the entity tag of the representation with the given encoding.
+    /// Compressed representations have their own strong validators, so that caches and
+    /// If-Range requests never mix the bytes of different encodings.
+    pub fn etag(&self, encoding: Encoding) -> String {
+        match encoding {
+            Encoding::Identity => self.etag.clone(),
+            _ => format!("\"{}-{}\"", self.etag.trim_matches('"'), encoding.name()),
+        }
+    }
+
+    pub fn cache_control(&self) -> &str {
+        &self.cache_control
+    }
+
+    /// Returns true for the entries of directories, which serve their index.html.
+    pub fn is_index(&self) -> bool {
+        self.path.file_name().map_or(false, |it| it == "index.html")
+    }
+
+    pub fn identity_len(&self) -> u64 {
+        self.identity_len
+    }
+
+    pub fn resident(&self) -> Option<&Resident> {
+        match &self.storage {
+            Storage::Memory { resident, .. } => Some(resident),
+            Storage::Disk { .. } => None,
+        }
+    }
+
+    /// Returns the bodies of an in-memory entry, reloading them from disk if they were evicted.
+    /// Fails with NotFound if the file changed since the entry was built.
+    async fn bodies(&self, resident: &Resident) -> Result<Arc<Bodies>> {
+        if let Some(bodies) = resident.get() {
+            return Ok(bodies);
+        }
+        let (digest, bodies) = load_bodies(&self.path, &self.content_type).await?;
+        if etag(digest) != self.etag {
+            return Err(Error::new(ErrorKind::NotFound, "File changed on disk"));
+        }
+        let bodies = resident.store(bodies);
+        enforce_budget();
+        Ok(bodies)
+    }
+
+    /// Loads (or opens, for streamed files) the body for the requested encoding.
+    /// Fails with NotFound if the file was removed or changed since the entry was built.
+    pub async fn body(&self, encoding: Encoding) -> Result<Body> {
+        match &self.storage {
+            Storage::Memory { resident, .. } => {
+                let bodies = self.bodies(resident).await?;
+                let (encoding, len) = match (encoding, bodies.br.as_ref(), bodies.gzip.as_ref()) {
+                    (Encoding::Brotli, Some(br), _) => (Encoding::Brotli, br.len()),
+                    (Encoding::Gzip, _, Some(gzip)) => (Encoding::Gzip, gzip.len()),
+                    _ => (Encoding::Identity, bodies.identity.len()),
+                };
+                Ok(Body {
+                    source: Source::Memory(bodies, encoding),
+                    encoding,
+                    len: len as u64,
+                })
+            }
+            Storage::Disk { br, gzip } => {
+                let file = File::open(&self.path).await?;
+                let meta = file.metadata().await?;
+                if meta.len() != self.stamp.len || meta.modified()? != self.stamp.modified {
+                    return Err(Error::new(ErrorKind::NotFound, "File changed on disk"));
+                }
+                let (encoding, file, len) = match (encoding, br.as_ref(), gzip.as_ref()) {
+                    (Encoding::Brotli, Some((path, len)), _) => {
+                        (Encoding::Brotli, File::open(path).await?, *len)
+                    }
+                    (Encoding::Gzip, _, Some((path, len))) => {
+                        (Encoding::Gzip, File::open(path).await?, *len)
+                    }
+                    _ => (Encoding::Identity, file, self.identity_len),
+                };
+                count_streamed();
+                Ok(Body {
+                    source: Source::Disk(file),
+                    encoding,
+                    len,
+                })
+            }
+        }
+    }
+
+    /// Writes the full body with the given status (200 OK, or an error status for error pages).
+    pub async fn write_full<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        status: &str,
+        mut body: Body,
+        cache_control: &str,
+        origin: Option<&str>,
+        with_body: bool,
+    ) -> Result<()> {
+        let header = self.header(
+            status,
+            cache_control,
+            &self.representation(body.encoding, ""),
+            &self.content_type,
+            body.len,
+            origin,
+        );
+        stream.write_all(&header).await?;
+        if with_body {
+            body.source.write_range(stream, 0..body.len).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the 206 response for the given ranges of the identity body.
+    /// Several ranges are sent as a multipart/byteranges body.
+    pub async fn write_partial<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        identity: Body,
+        ranges: &[Range<u64>],
+        cache_control: &str,
+        origin: Option<&str>,
+        with_body: bool,
+    ) -> Result<()> {
+        let Body {
+            mut source, len, ..
+        } = identity;
+        if let [range] = ranges {
+            let content_range = format!(
+                "Content-Range: bytes {}-{}/{}\r\n",
+                range.start,
+                range.end - 1,
+                len
+            );
+            let header = self.header(
+                "206 Partial Content",
+                cache_control,
+                &self.representation(Encoding::Identity, &content_range),
+                &self.content_type,
+                range.end - range.start,
+                origin,
+            );
+            stream.write_all(&header).await?;
+            if with_body {
+                source.write_range(stream, range.clone()).await?;
+            }
+            return Ok(());
+        }
+        let boundary = format!("byteranges-{}", self.etag.trim_matches('"'));
+        let parts: Vec<String> = ranges
+            .iter()
+            .map(|range| {
                 format!(
-                    "\
-HTTP/1.1 200 OK\r\n\
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    self.content_type,
+                    range.start,
+                    range.end - 1,
+                    len
+                )
+            })
+            .collect();
+        let end = format!("\r\n--{}--\r\n", boundary);
+        let body_len = parts.iter().map(|it| it.len() as u64).sum::<u64>()
+            + ranges.iter().map(|it| it.end - it.start).sum::<u64>()
+            + end.len() as u64;
+        let header = self.header(
+            "206 Partial Content",
+            cache_control,
+            &self.representation(Encoding::Identity, ""),
+            &format!("multipart/byteranges; boundary={}", boundary),
+            body_len,
+            origin,
+        );
+        stream.write_all(&header).await?;
+        if with_body {
+            for (part, range) in parts.iter().zip(ranges.iter()) {
+                stream.write_all(part.as_bytes()).await?;
+                source.write_range(stream, range.clone()).await?;
+            }
+            stream.write_all(end.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the 416 response for a Range header that doesn't overlap the identity body.
+    pub fn range_not_satisfiable(&self) -> Vec<u8> {
+        format!(
+            "\
+HTTP/1.1 416 Range Not Satisfiable\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Content-Range: bytes */{}\r\n\
+Content-Length: 0\r\n\
+\r\n",
+            self.identity_len
+        )
+        .into_bytes()
+    }
+
+    /// Returns the 304 response for the representation with the given encoding.
+    pub fn not_modified(&self, cache_control: &str, encoding: Encoding) -> Vec<u8> {
+        format!(
+            "\
+HTTP/1.1 304 Not Modified\r\n\
 Cache-Control: {}\r\n\
 Connection: close\r\n\
-{}\
 ETag: {}\r\n\
+Last-Modified: {}\r\n\
+Vary: Origin, Cookie, Accept-Encoding\r\n\
+\r\n",
+            cache_control,
+            self.etag(encoding),
+            fmt_http_date(self.last_modified)
+        )
+        .into_bytes()
+    }
+
+    // The headers that depend on the representation being sent.
+    fn representation(&self, encoding: Encoding, content_range: &str) -> String {
+        format!(
+            "{}{}ETag: {}\r\n",
+            encoding.header(),
+            content_range,
+            self.etag(encoding)
+        )
+    }
+
+    fn header(
+        &self,
+        status: &str,
+        cache_control: &str,
+        representation: &str,
+        content_type: &str,
+        len: u64,
+        origin: Option<&str>,
+    ) -> Vec<u8> {
+        format!(
+            "\
+HTTP/1.1 {}\r\n\
+Cache-Control: {}\r\n\
+Connection: close\r\n\
+{}\
+Last-Modified: {}\r\n\
+Accept-Ranges: bytes\r\n\
 Content-Type: {}\r\n\
 Content-Length: {}\r\n\
+X-Integrity: {}\r\n\
 X-Content-Type-Options: nosniff\r\n\
-X-Frame-Options: DENY\r\n\
 X-XSS-Protection: 1; mode=block\r\n\
-Cross-Origin-Resource-Policy: same-origin\r\n\
-Cross-Origin-Embedder-Policy: require-corp\r\n\
-Cross-Security-Policy: default-src 'self' 'unsafe-inline'; worker-src 'self'; frame-src 'none'; object-src 'none'; base-uri 'none'; frame-ancestors 'none'\r\n\
-Access-Control-Allow-Origin: https://packurl.net\r\n\
-Access-Control-Max-Age: 86400\r\n\
-Strict-Transport-Security: max-age=63072000; includeSubDomains; preload\r\n\
-Vary: Origin, Cookie\r\n
-\r\n",
-                    cache_control,
-                    compressed,
-                    etag,
-                    content_type,
-                    data.len()
-                )
-                .into_bytes(),
-                data,
-            ]
-            .concat(),
-            not_modified: format!(
-                "\
-HTTP/1.1 304 Not Modified\r\n\
-Cache-Control: {}\r\n\
-Connection: close\r\n\
-ETag: {}\r\n\
+{}\
+Vary: Origin, Cookie, Accept-Encoding\r\n\
 \r\n",
-                cache_control, etag
-            )
-            .into_bytes(),
-            etag,
-        })
-    } else {
-        Err(Error::new(
-            ErrorKind::Other,
-            "Could not extract filename from path",
-        ))
+            status,
+            cache_control,
+            representation,
+            fmt_http_date(self.last_modified),
+            content_type,
+            len,
+            self.integrity,
+            self.policy.headers(origin)
+        )
+        .into_bytes()
+    }
+}
+
+async fn stamp(path: &Path) -> Result<Stamp> {
+    let meta = metadata(path).await?;
+    Ok(Stamp {
+        len: meta.len(),
+        modified: meta.modified()?,
+        br: sibling_stamp(path, "br").await,
+        gzip: sibling_stamp(path, "gz").await,
+    })
+}
+
+async fn sibling_stamp(path: &Path, extension: &str) -> Option<(u64, SystemTime)> {
+    let meta = metadata(sibling(path, extension)?).await.ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+// A strong validator derived from the content only, so that it is the same on every server.
+fn etag(digest: Digest) -> String {
+    format!(
+        "\"{}\"",
+        base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+// Large files are hashed in chunks rather than read at once.
+// Returns the sha256 digest (for the etag) and the sha384 digest (for the integrity value).
+async fn file_digests(path: &Path) -> Result<(Digest, Digest)> {
+    let mut file = File::open(path).await?;
+    let mut context = Context::new(&SHA256);
+    let mut sri_context = Context::new(&SHA384);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+        sri_context.update(&buf[..n]);
+    }
+    Ok((context.finish(), sri_context.finish()))
+}
+
+fn sibling(path: &Path, extension: &str) -> Option<PathBuf> {
+    let filename = path.file_name()?.to_str()?;
+    path.parent()
+        .map(|it| it.join(format!("{}.{}", filename, extension)))
+}
+
+/// Reads the file and its compressed versions (precompressed siblings, or compressed on the fly).
+async fn load_bodies(path: &Path, content_type: &str) -> Result<(Digest, Bodies)> {
+    let identity = Arc::new(read(path).await?);
+    let digest = digest(&SHA256, &identity);
+    let etag = etag(digest);
+    let mut br = match sibling(path, "br") {
+        Some(compressed_path) => read(compressed_path).await.ok(),
+        None => None,
+    };
+    let mut gzip = match sibling(path, "gz") {
+        Some(compressed_path) => read(compressed_path).await.ok(),
+        None => None,
+    };
+    if is_compressible(content_type) {
+        if br.is_none() {
+            br = compress(path, &etag, identity.clone(), Encoding::Brotli).await;
+        }
+        if gzip.is_none() {
+            gzip = compress(path, &etag, identity.clone(), Encoding::Gzip).await;
+        }
+    }
+    let bodies = Bodies {
+        identity: Arc::try_unwrap(identity).unwrap_or_else(|it| it.as_ref().clone()),
+        br,
+        gzip,
+    };
+    Ok((digest, bodies))
+}
+
+async fn stat(path: Option<PathBuf>) -> Option<(PathBuf, u64)> {
+    let path = path?;
+    let len = metadata(&path).await.ok()?.len();
+    Some((path, len))
+}
+
+// Returns the precompressed sibling of a large file, or its version from the compression cache.
+async fn compressed_file(
+    path: &Path,
+    etag: &str,
+    content_type: &str,
+    encoding: Encoding,
+) -> Option<(PathBuf, u64)> {
+    let extension = match encoding {
+        Encoding::Brotli => "br",
+        Encoding::Gzip => "gz",
+        Encoding::Identity => return None,
+    };
+    match stat(sibling(path, extension)).await {
+        Some(it) => Some(it),
+        None if is_compressible(content_type) => compress_file(path, etag, encoding).await,
+        None => None,
     }
 }
 
+async fn build_response(
+    path: &Path,
+    policy: Policy,
+    stamp: Stamp,
+    cache_control: &str,
+    content_type: &str,
+) -> Result<FileEntry> {
+    let (digest, sri_digest, storage) = if stamp.len > *STREAM_THRESHOLD {
+        let (digest, sri_digest) = file_digests(path).await?;
+        let storage = Storage::Disk {
+            br: compressed_file(path, &etag(digest), content_type, Encoding::Brotli).await,
+            gzip: compressed_file(path, &etag(digest), content_type, Encoding::Gzip).await,
+        };
+        (digest, sri_digest, storage)
+    } else {
+        let (digest, bodies) = load_bodies(path, content_type).await?;
+        let sri_digest = ring::digest::digest(&SHA384, &bodies.identity);
+        let mut encodings = vec![Encoding::Identity];
+        if bodies.br.is_some() {
+            encodings.push(Encoding::Brotli);
+        }
+        if bodies.gzip.is_some() {
+            encodings.push(Encoding::Gzip);
+        }
+        let storage = Storage::Memory {
+            resident: Resident::new(bodies),
+            encodings,
+        };
+        (digest, sri_digest, storage)
+    };
+    // HTTP dates have a resolution of one second
+    let last_modified = UNIX_EPOCH
+        + Duration::from_secs(
+            stamp
+                .modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |it| it.as_secs()),
+        );
+    Ok(FileEntry {
+        etag: etag(digest),
+        last_modified,
+        stamp,
+        cache_control: cache_control.to_string(),
+        content_type: content_type.to_string(),
+        path: path.to_path_buf(),
+        identity_len: stamp.len,
+        storage,
+        hash: content_hash(&digest),
+        integrity: sri(sri_digest),
+        policy,
+    })
+}
+
 #[async_recursion]
-async fn walk(path: &Path) -> Result<()> {
-    let stat = metadata(path).await?;
-    if stat.is_dir() {
-        let mut iterator = read_dir(path).await?;
-        while let Some(entry) = iterator.next_entry().await? {
-            if let Some(file_name) = entry.file_name().to_str() {
-                if !file_name.starts_with('.') {
+async fn walk(path: &Path, snapshot: &mut Snapshot) -> Result<()> {
+    match visit(&snapshot.root, path, &snapshot.ancestors).await? {
+        Visit::Dir(canonical) => {
+            snapshot.ancestors.push(canonical);
+            let mut iterator = read_dir(path).await?;
+            while let Some(entry) = iterator.next_entry().await? {
+                if let Some(file_name) = entry.file_name().to_str() {
                     let path = path.join(file_name);
-                    walk(path.as_path()).await?;
+                    if is_hidden(file_name) {
+                        snapshot.skip(&path, "hidden");
+                    } else {
+                        walk(path.as_path(), snapshot).await?;
+                    }
                 }
             }
+            snapshot.ancestors.pop();
         }
-    }
-    if stat.is_file() {
-        if let Some(uri_path) = UriPath::from(PREFIX.as_str(), ROOT.as_str(), path) {
-            if let Some(parent) = uri_path.parent() {
-                if let Some(filename) = path
-                    .file_name()
-                    .and_then(|it| it.to_str().map(|it| it.to_lowercase()))
-                {
-                    if filename == "index.html" {
-                        if let Ok(etag) = etag(path).await {
-                            let key = parent.to_string();
-                            let stored = FILES.get(&key);
-                            let update = stored.is_some();
-                            let insert = if let Some(stored) = stored {
-                                stored.etag != etag
+        Visit::File => {
+            let site = snapshot.site;
+            if let Some(uri_path) = UriPath::from(site.prefix.as_str(), site.root.as_str(), path) {
+                if let Some(parent) = uri_path.parent() {
+                    // keys keep the case of the filename, like request targets
+                    if let Some(filename) = path.file_name().and_then(|it| it.to_str()) {
+                        if let Some(mime) = MimeType::for_filename(filename) {
+                            let key = if filename == "index.html" {
+                                parent.into_dir().to_string()
                             } else {
-                                true
+                                parent.join(filename).to_string()
                             };
-                            if insert {
-                                if update {
-                                    LogLevel::Debug.log(|| {
-                                        println!("{}", format!("Updating {}", key.yellow()))
-                                    });
-                                } else {
-                                    LogLevel::Debug.log(|| {
-                                        println!("{}", format!("Adding   {}", key.green()))
-                                    });
-                                }
-                                let _ = FILES.insert(
-                                    key,
-                                    build_response(path, etag, "public,no-cache", "text/html")
-                                        .await?,
-                                );
-                            }
-                        }
-                    } else if let Some((cache_control, content_type)) =
-                        cache_control_and_content_type(&filename)
-                    {
-                        if let Ok(etag) = etag(path).await {
-                            let key = parent.join(&filename).to_string();
-                            let stored = FILES.get(&key);
-                            let update = stored.is_some();
-                            let insert = if let Some(stored) = stored {
-                                stored.etag != etag
-                            } else {
-                                true
-                            };
-                            if insert {
-                                if update {
-                                    LogLevel::Debug.log(|| {
-                                        println!("{}", format!("Updating {}", key.yellow()))
-                                    });
-                                } else {
-                                    LogLevel::Debug.log(|| {
-                                        println!("{}", format!("Adding   {}", key.green()))
-                                    });
-                                }
-                                let _ = FILES.insert(
-                                    key,
-                                    build_response(path, etag, cache_control, content_type).await?,
-                                );
-                            }
+                            snapshot.add(path, key, mime).await?;
                         }
                     }
                 }
             }
         }
+        Visit::Skip(reason) => snapshot.skip(path, reason),
+        Visit::Other => {}
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::cache::Cache;
+    use crate::cdn::encoding::Encoding;
+    use crate::cdn::site::Site;
+    use std::fs::{create_dir, remove_file, write};
+    use std::os::unix::fs::symlink;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn snapshot_updates() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        // images aren't compressed, so that nothing is written to the compression cache
+        write(root.join("a.png"), "a").unwrap();
+        write(root.join("b.png"), "b").unwrap();
+        write(root.join("c.png"), "c").unwrap();
+        let site: &'static Site = Site::for_test(root);
+
+        let summary = Cache::update_site(site).await.unwrap();
+        assert_eq!(
+            summary.added,
+            vec![
+                "test.packurl.net/a.png",
+                "test.packurl.net/b.png",
+                "test.packurl.net/c.png"
+            ]
+        );
+        assert!(summary.updated.is_empty() && summary.removed.is_empty());
+        let previous = site.files.load_full();
+
+        write(root.join("b.png"), "bb").unwrap();
+        remove_file(root.join("c.png")).unwrap();
+        let summary = Cache::update_site(site).await.unwrap();
+        assert!(summary.added.is_empty());
+        assert_eq!(summary.updated, vec!["test.packurl.net/b.png"]);
+        assert_eq!(summary.removed, vec!["test.packurl.net/c.png"]);
+
+        let files = site.files.load_full();
+        let mut keys: Vec<&String> = files.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["/a.png", "/b.png"]);
+        assert_eq!(files["/b.png"].identity_len(), 2);
+        // unchanged entries are shared, and the previous version is left untouched
+        assert!(Arc::ptr_eq(&files["/a.png"], &previous["/a.png"]));
+        assert_eq!(previous.len(), 3);
+        assert_eq!(previous["/b.png"].identity_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn root_replaced() {
+        let temp = tempdir().unwrap();
+        let (one, two, root) = (
+            temp.path().join("one"),
+            temp.path().join("two"),
+            temp.path().join("www"),
+        );
+        create_dir(&one).unwrap();
+        create_dir(&two).unwrap();
+        write(one.join("a.png"), "a").unwrap();
+        write(one.join("b.png"), "b").unwrap();
+        write(two.join("a.png"), "a").unwrap();
+        symlink(&one, &root).unwrap();
+        let site: &'static Site = Site::for_test(&root);
+        Cache::update_site(site).await.unwrap();
+        assert_eq!(site.files.load().len(), 2);
+
+        // swap the symlink, the way a deployment does
+        symlink(&two, temp.path().join("next")).unwrap();
+        std::fs::rename(temp.path().join("next"), &root).unwrap();
+        let summary = Cache::update_paths(site, vec![root.clone()]).await.unwrap();
+        assert_eq!(summary.removed, vec!["test.packurl.net/b.png"]);
+        let files = site.files.load_full();
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec!["/a.png"]);
+    }
+
+    #[tokio::test]
+    async fn precompressed_siblings() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        write(root.join("a.png"), "a").unwrap();
+        write(root.join("a.png.br"), "b").unwrap();
+        let site: &'static Site = Site::for_test(root);
+        Cache::update_site(site).await.unwrap();
+        let body = site.files.load()["/a.png"].body(Encoding::Brotli).await;
+        assert_eq!(body.unwrap().len, 1);
+
+        // only the sibling changes
+        write(root.join("a.png.br"), "bbb").unwrap();
+        let summary = Cache::update_paths(site, vec![root.join("a.png.br")])
+            .await
+            .unwrap();
+        assert_eq!(summary.updated, vec!["test.packurl.net/a.png"]);
+        let body = site.files.load()["/a.png"].body(Encoding::Brotli).await;
+        assert_eq!(body.unwrap().len, 3);
+
+        remove_file(root.join("a.png.br")).unwrap();
+        let summary = Cache::update_site(site).await.unwrap();
+        assert_eq!(summary.updated, vec!["test.packurl.net/a.png"]);
+        assert_eq!(
+            site.files.load()["/a.png"].encodings(),
+            vec![Encoding::Identity]
+        );
+    }
+}
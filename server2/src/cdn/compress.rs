@@ -0,0 +1,218 @@
+use crate::acme::STATE_DIRECTORY;
+use crate::cdn::encoding::Encoding;
+use crate::log::LogLevel;
+use brotli::enc::BrotliEncoderParams;
+use brotli::BrotliCompress;
+use colored::Colorize;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use lazy_static::lazy_static;
+use ring::digest::{digest, SHA256};
+use std::env::var;
+use std::fs::File;
+use std::io::{copy, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{create_dir_all, metadata, read, rename, write};
+use tokio::task::spawn_blocking;
+
+lazy_static! {
+    static ref BROTLI_QUALITY: i32 = var("XDG_WWW_BROTLI_QUALITY")
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(11)
+        .clamp(0, 11);
+    static ref GZIP_LEVEL: u32 = var("XDG_WWW_GZIP_LEVEL")
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(9)
+        .min(9);
+    static ref CACHE_DIR: String = var("XDG_WWW_COMPRESSION_CACHE")
+        .unwrap_or_else(|_| format!("{}/compressed", STATE_DIRECTORY.as_str()));
+}
+
+pub fn is_compressible(content_type: &str) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    content_type.starts_with("text/")
+        || matches!(
+            content_type,
+            "application/javascript"
+                | "application/json"
+                | "application/manifest+json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "model/gltf-binary"
+        )
+}
+
+/// Compresses the data, reusing the result from a previous run if the file hasn't changed.
+/// Returns None if compression doesn't make the data smaller.
+pub async fn compress(
+    path: &Path,
+    etag: &str,
+    data: Arc<Vec<u8>>,
+    encoding: Encoding,
+) -> Option<Vec<u8>> {
+    let cached = cache_path(path, etag, encoding);
+    if let Ok(compressed) = read(&cached).await {
+        // an empty file records that compression didn't help
+        return if compressed.is_empty() {
+            None
+        } else {
+            Some(compressed)
+        };
+    }
+    LogLevel::Debug.log(|| {
+        println!(
+            "{}",
+            format!("Compressing {} ({})", path.display(), encoding.name()).dimmed()
+        )
+    });
+    let len = data.len();
+    let compressed = match spawn_blocking(move || compress_sync(&data, encoding)).await {
+        Ok(Ok(compressed)) if compressed.len() < len => Some(compressed),
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => {
+            LogLevel::Warning.log(|| {
+                println!("{}", format!("Failed to compress {}", path.display()).red());
+                println!("{:?}", err);
+            });
+            return None;
+        }
+        Err(_) => return None,
+    };
+    let empty = Vec::new();
+    if let Err(err) = persist(&cached, compressed.as_ref().unwrap_or(&empty)).await {
+        LogLevel::Warning.log(|| {
+            println!("{}", "Failed to persist compressed file".red());
+            println!("{:?}", err);
+        });
+    }
+    compressed
+}
+
+/// Compresses a file that is too large to be held in memory into the compression cache,
+/// streaming it rather than reading it at once.
+/// Returns the path and length of the compressed file, or None if compression doesn't help.
+pub async fn compress_file(path: &Path, etag: &str, encoding: Encoding) -> Option<(PathBuf, u64)> {
+    let cached = cache_path(path, etag, encoding);
+    if let Ok(meta) = metadata(&cached).await {
+        // an empty file records that compression didn't help
+        return Some((cached, meta.len())).filter(|(_, len)| *len > 0);
+    }
+    LogLevel::Debug.log(|| {
+        println!(
+            "{}",
+            format!("Compressing {} ({})", path.display(), encoding.name()).dimmed()
+        )
+    });
+    let result = async {
+        if let Some(parent) = cached.parent() {
+            create_dir_all(parent).await?;
+        }
+        let partial = partial_path(&cached);
+        let (source, target) = (path.to_path_buf(), partial.clone());
+        let (len, compressed_len) =
+            spawn_blocking(move || compress_file_sync(&source, &target, encoding))
+                .await
+                .map_err(|err| Error::new(ErrorKind::Other, err))??;
+        if compressed_len >= len {
+            write(&partial, b"").await?;
+        }
+        rename(&partial, &cached).await?;
+        Ok::<u64, Error>(if compressed_len < len {
+            compressed_len
+        } else {
+            0
+        })
+    }
+    .await;
+    match result {
+        Ok(len) => Some((cached, len)).filter(|(_, len)| *len > 0),
+        Err(err) => {
+            LogLevel::Warning.log(|| {
+                println!("{}", format!("Failed to compress {}", path.display()).red());
+                println!("{:?}", err);
+            });
+            None
+        }
+    }
+}
+
+// Returns the lengths of the source and of the compressed file.
+fn compress_file_sync(source: &Path, target: &Path, encoding: Encoding) -> Result<(u64, u64)> {
+    let source = File::open(source)?;
+    let len = source.metadata()?.len();
+    let mut reader = BufReader::new(source);
+    let mut writer = BufWriter::new(File::create(target)?);
+    match encoding {
+        Encoding::Brotli => {
+            let params = BrotliEncoderParams {
+                quality: *BROTLI_QUALITY,
+                ..Default::default()
+            };
+            BrotliCompress(&mut reader, &mut writer, &params)?;
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(&mut writer, Compression::new(*GZIP_LEVEL));
+            copy(&mut reader, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Encoding::Identity => {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not a compression"));
+        }
+    };
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    Ok((len, file.metadata()?.len()))
+}
+
+fn compress_sync(data: &[u8], encoding: Encoding) -> Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let params = BrotliEncoderParams {
+                quality: *BROTLI_QUALITY,
+                ..Default::default()
+            };
+            let mut compressed = Vec::with_capacity(data.len() / 2);
+            BrotliCompress(&mut &data[..], &mut compressed, &params)?;
+            Ok(compressed)
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(
+                Vec::with_capacity(data.len() / 2),
+                Compression::new(*GZIP_LEVEL),
+            );
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Identity => Err(Error::new(ErrorKind::InvalidInput, "Not a compression")),
+    }
+}
+
+fn cache_path(path: &Path, etag: &str, encoding: Encoding) -> PathBuf {
+    let hash = digest(&SHA256, format!("{}\n{}", path.display(), etag).as_bytes());
+    let name: String = hash.as_ref()[..16]
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect();
+    Path::new(CACHE_DIR.as_str()).join(format!("{}.{}", name, encoding.name()))
+}
+
+// Compressed files are written aside and renamed, so that a partial file is never taken for a
+// complete one. The encoding is kept in the name, as both encodings can be written at once.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+async fn persist(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).await?;
+    }
+    let partial = partial_path(path);
+    write(&partial, data).await?;
+    rename(&partial, path).await
+}
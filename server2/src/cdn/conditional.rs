@@ -0,0 +1,89 @@
+use httpdate::parse_http_date;
+use std::time::SystemTime;
+
+/// Returns true if the If-None-Match header value matches the entity tag.
+/// This uses the weak comparison: `W/"x"` matches `"x"`.
+pub fn none_match(if_none_match: &str, etag: &str) -> bool {
+    let etag = opaque_tag(etag);
+    if_none_match
+        .split(',')
+        .map(|it| it.trim())
+        .any(|it| it == "*" || (it.ends_with('"') && opaque_tag(it) == etag))
+}
+
+/// Returns true if the If-Range header value still designates the current entity.
+/// Entity tags use the strong comparison, so weak tags never match.
+pub fn range_matches(if_range: &str, etag: &str, last_modified: SystemTime) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') {
+        if_range == etag
+    } else if if_range.starts_with("W/") {
+        false
+    } else {
+        parse_http_date(if_range).map_or(false, |it| it == last_modified)
+    }
+}
+
+/// Returns true if the entity hasn't changed since the If-Modified-Since header value.
+/// Invalid dates are ignored.
+pub fn not_modified_since(if_modified_since: &str, last_modified: SystemTime) -> bool {
+    parse_http_date(if_modified_since.trim()).map_or(false, |it| last_modified <= it)
+}
+
+fn opaque_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::conditional::{none_match, not_modified_since, range_matches};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn if_none_match() {
+        assert!(none_match("\"abc\"", "\"abc\""));
+        assert!(none_match("W/\"abc\"", "\"abc\""));
+        assert!(none_match("\"x\", W/\"abc\" ,\"y\"", "\"abc\""));
+        assert!(none_match("*", "\"abc\""));
+        assert!(!none_match("\"abd\"", "\"abc\""));
+        assert!(!none_match("abc", "\"abc\""));
+        assert!(!none_match("", "\"abc\""));
+    }
+
+    #[test]
+    fn if_range() {
+        let last_modified = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert!(range_matches("\"abc\"", "\"abc\"", last_modified));
+        assert!(!range_matches("W/\"abc\"", "\"abc\"", last_modified));
+        // a range of a compressed representation never applies to the identity body
+        assert!(!range_matches("\"abc-br\"", "\"abc\"", last_modified));
+        assert!(range_matches(
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "\"abc\"",
+            last_modified
+        ));
+        assert!(!range_matches(
+            "Sun, 06 Nov 1994 08:49:38 GMT",
+            "\"abc\"",
+            last_modified
+        ));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let last_modified = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert!(not_modified_since(
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            last_modified
+        ));
+        assert!(not_modified_since(
+            "Mon, 07 Nov 1994 08:49:37 GMT",
+            last_modified
+        ));
+        assert!(!not_modified_since(
+            "Sat, 05 Nov 1994 08:49:37 GMT",
+            last_modified
+        ));
+        assert!(!not_modified_since("yesterday", last_modified));
+    }
+}
@@ -0,0 +1,58 @@
+use lazy_static::lazy_static;
+use std::env::var;
+
+lazy_static! {
+    static ref METHODS: Vec<String> = var("XDG_CORS_METHODS")
+        .unwrap_or("GET, HEAD, OPTIONS".to_string())
+        .split(',')
+        .map(|it| it.trim().to_ascii_uppercase())
+        .filter(|it| !it.is_empty())
+        .collect();
+    static ref HEADERS: String = var("XDG_CORS_HEADERS")
+        .unwrap_or("Range, If-Range, If-None-Match, If-Modified-Since".to_string());
+}
+
+pub fn allowed_origin(origins: &[String], origin: &str) -> bool {
+    origins
+        .iter()
+        .any(|it| it == "*" || it.eq_ignore_ascii_case(origin))
+}
+
+/// Returns the response to an OPTIONS request.
+/// CORS preflights from an allowed origin for an allowed method get the Access-Control headers,
+/// anything else only gets the list of supported methods, which the browser treats as a refusal.
+pub fn preflight_response(
+    origins: &[String],
+    origin: Option<&str>,
+    method: Option<&str>,
+) -> Vec<u8> {
+    let cors = match (origin, method) {
+        (Some(origin), Some(method))
+            if allowed_origin(origins, origin) && METHODS.iter().any(|it| it == method) =>
+        {
+            format!(
+                "\
+Access-Control-Allow-Origin: {}\r\n\
+Access-Control-Allow-Methods: {}\r\n\
+Access-Control-Allow-Headers: {}\r\n\
+Access-Control-Max-Age: 86400\r\n",
+                origin,
+                METHODS.join(", "),
+                HEADERS.as_str()
+            )
+        }
+        _ => String::new(),
+    };
+    format!(
+        "\
+HTTP/1.1 204 No Content\r\n\
+Allow: GET, HEAD, OPTIONS\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+{}\
+Vary: Origin, Access-Control-Request-Method, Access-Control-Request-Headers\r\n\
+\r\n",
+        cors
+    )
+    .into_bytes()
+}
@@ -0,0 +1,156 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    /// Encodings in order of preference when the client accepts several with the same weight.
+    pub const PREFERENCE: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "Content-Encoding: br\r\n",
+            Encoding::Gzip => "Content-Encoding: gzip\r\n",
+            Encoding::Identity => "",
+        }
+    }
+}
+
+/// Picks the best of the available encodings for the given Accept-Encoding header value.
+/// Returns None if the client explicitly refuses all of them (including identity).
+pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let accept_encoding = match accept_encoding {
+        Some(it) => it,
+        None => {
+            return if available.contains(&Encoding::Identity) {
+                Some(Encoding::Identity)
+            } else {
+                None
+            }
+        }
+    };
+    let weights: Vec<(&str, u16)> = accept_encoding
+        .split(',')
+        .filter_map(|it| {
+            let mut parts = it.split(';');
+            let coding = parts.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+            let weight = parts
+                .find_map(|it| {
+                    let (name, value) = it.split_once('=')?;
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        Some(parse_weight(value.trim()))
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(1000);
+            Some((coding, weight))
+        })
+        .collect();
+    let weight = |encoding: Encoding| {
+        let find = |name: &str| {
+            weights
+                .iter()
+                .find(|(it, _)| it.eq_ignore_ascii_case(name))
+                .map(|(_, weight)| *weight)
+        };
+        find(encoding.name())
+            .or_else(|| {
+                if encoding == Encoding::Gzip {
+                    find("x-gzip")
+                } else {
+                    None
+                }
+            })
+            .or_else(|| find("*"))
+            .unwrap_or(if encoding == Encoding::Identity { 1 } else { 0 })
+    };
+    Encoding::PREFERENCE
+        .iter()
+        .filter(|it| available.contains(it))
+        .map(|&it| (it, weight(it)))
+        .filter(|(_, weight)| *weight > 0)
+        .fold(
+            None,
+            |best: Option<(Encoding, u16)>, (it, weight)| match best {
+                Some((_, best_weight)) if best_weight >= weight => best,
+                _ => Some((it, weight)),
+            },
+        )
+        .map(|(it, _)| it)
+}
+
+// q-values have at most 3 decimals, so they are stored as an integer between 0 and 1000.
+fn parse_weight(value: &str) -> u16 {
+    let (integer, decimals) = value.split_once('.').unwrap_or((value, ""));
+    if integer == "1" {
+        return 1000;
+    }
+    if integer != "0" && !integer.is_empty() {
+        return 0;
+    }
+    decimals
+        .chars()
+        .chain("000".chars())
+        .take(3)
+        .try_fold(0u16, |acc, it| {
+            it.to_digit(10).map(|it| acc * 10 + it as u16)
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::encoding::{negotiate, Encoding};
+
+    const ALL: [Encoding; 3] = [Encoding::Identity, Encoding::Brotli, Encoding::Gzip];
+
+    #[test]
+    fn missing_header() {
+        assert_eq!(negotiate(None, &ALL), Some(Encoding::Identity));
+        assert_eq!(negotiate(None, &[Encoding::Brotli]), None);
+    }
+
+    #[test]
+    fn preference() {
+        assert_eq!(
+            negotiate(Some("gzip, deflate, br"), &ALL),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(negotiate(Some("gzip, deflate"), &ALL), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("deflate"), &ALL), Some(Encoding::Identity));
+        assert_eq!(negotiate(Some(""), &ALL), Some(Encoding::Identity));
+    }
+
+    #[test]
+    fn weights() {
+        assert_eq!(
+            negotiate(Some("br;q=0.5, gzip;q=0.8"), &ALL),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(Some("br;q=0, *"), &ALL), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(Some("*;q=0.1, identity;q=0.5"), &ALL),
+            Some(Encoding::Identity)
+        );
+        assert_eq!(
+            negotiate(Some("br, identity;q=0"), &[Encoding::Identity]),
+            None
+        );
+        assert_eq!(negotiate(Some("*;q=0"), &ALL), None);
+        assert_eq!(negotiate(Some("BR;Q=1.000"), &ALL), Some(Encoding::Brotli));
+    }
+}
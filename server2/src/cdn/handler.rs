@@ -1,38 +1,37 @@
-use crate::cdn::cache::{CDN_ROOT, FILES};
+use crate::cdn::conditional::{none_match, not_modified_since, range_matches};
+use crate::cdn::cors::preflight_response;
+use crate::cdn::encoding::{negotiate, Encoding};
+use crate::cdn::hashed::{is_manifest, manifest, parse_hashed_path};
+use crate::cdn::integrity::{integrity_index, is_integrity_index};
+use crate::cdn::listing::listing;
+use crate::cdn::mime::IMMUTABLE;
+use crate::cdn::pages::{spa_fallback, ErrorPage};
+use crate::cdn::path::{percent_encode, UriPath};
+use crate::cdn::range::{parse_ranges, ByteRanges};
+use crate::cdn::site::Site;
+use crate::http::{read_request, Limits, Method, Request};
 use crate::log::LogLevel;
 use colored::Colorize;
 use std::io::Result;
-use std::str::from_utf8;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 
-const NOT_FOUND_RESPONSE: &[u8] = b"HTTP/1.1 404 Not Found\r\n\
+const METHOD_NOT_ALLOWED_RESPONSE: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\n\
 Cache-Control: no-cache\r\n\
+Allow: GET, HEAD, OPTIONS\r\n\
 Connection: close\r\n\
 Content-Length: 0\r\n\
 \r\n";
-const PAYLOAD_TOO_LARGE_RESPONSE: &[u8] = b"HTTP/1.1 413 Payload Too Large\r\n\
+const NOT_ACCEPTABLE_RESPONSE: &[u8] = b"HTTP/1.1 406 Not Acceptable\r\n\
 Cache-Control: no-cache\r\n\
 Connection: close\r\n\
 Content-Length: 0\r\n\
+Vary: Accept-Encoding\r\n\
 \r\n";
-const METHOD_NOT_ALLOWED_RESPONSE: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\n\
-Cache-Control: no-cache\r\n\
-Allow: GET, OPTIONS\r\n\
-Connection: close\r\n\
-Content-Length: 0\r\n
-\r\n";
-const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
-Cache-Control: no-cache\r\n\
-Connection: close\r\n\
-Content-Length: 0\r\n
-\r\n";
-
-const MAX_REQUEST_SIZE: usize = 4096;
 
-pub async fn handle_cdn_request(stream: &mut TlsStream<TcpStream>) {
-    if let Err(err) = handle_file_request(stream).await {
+pub async fn handle_cdn_request(stream: &mut TlsStream<TcpStream>, site: &'static Site) {
+    if let Err(err) = handle_file_request(stream, site).await {
         LogLevel::Warning.log(|| {
             println!("{}", "Failed to accept TLS connection".red());
             println!("{:?}", err);
@@ -40,117 +39,267 @@ pub async fn handle_cdn_request(stream: &mut TlsStream<TcpStream>) {
     }
 }
 
-async fn read_request(stream: &mut TlsStream<TcpStream>) -> Result<Option<Vec<u8>>> {
-    let mut len: usize = 0;
-    let mut bounded = stream.take(MAX_REQUEST_SIZE as u64);
-    loop {
-        let mut buf: [u8; 1600] = [0u8; 1600];
-        let n = bounded.read(&mut buf[len..]).await?;
-        let start = if len > 2 { len - 2 } else { len };
-        len += n;
-        let scan = &buf[start..len];
-        if scan.len() > 3 {
-            if let Some(pos) = scan.windows(4).position(|it| it == b"\r\n\r\n") {
-                return Ok(Some(buf[..pos + start].to_vec()));
+async fn handle_file_request(stream: &mut TlsStream<TcpStream>, site: &'static Site) -> Result<()> {
+    let request = match read_request(stream, &Limits::default()).await {
+        Ok((request, _)) => request,
+        Err(err) => {
+            if let Some(response) = err.response() {
+                let _ = stream.write_all(&response).await;
             }
+            return Ok(());
+        }
+    };
+    LogLevel::Debug.log(|| println!("{}", format!("{:?}", request).dimmed()));
+    match request.method {
+        Method::Get => serve_file(stream, site, &request, true).await,
+        Method::Head => serve_file(stream, site, &request, false).await,
+        Method::Options => {
+            let policy = UriPath::parse(site.prefix.as_str(), &request.target)
+                .map(|it| site.rules.policy(&it.to_string()))
+                .unwrap_or_default();
+            let response = preflight_response(
+                &policy.cors_origins,
+                request.headers.get("origin"),
+                request.headers.get("access-control-request-method"),
+            );
+            let _ = stream.write_all(&response).await;
+            Ok(())
         }
-        if scan.len() == MAX_REQUEST_SIZE {
-            return Ok(None);
+        _ => {
+            let _ = stream.write_all(METHOD_NOT_ALLOWED_RESPONSE).await;
+            Ok(())
         }
     }
 }
 
-async fn handle_file_request(stream: &mut TlsStream<TcpStream>) -> Result<()> {
-    match read_request(stream).await? {
+// HEAD requests get exactly the same headers as GET requests, without the body.
+async fn serve_file(
+    stream: &mut TlsStream<TcpStream>,
+    site: &'static Site,
+    request: &Request,
+    with_body: bool,
+) -> Result<()> {
+    let path = request.target.as_str();
+    LogLevel::Info.log(|| println!("{}", path));
+    let headers = &request.headers;
+    let key = match UriPath::parse(site.prefix.as_str(), path) {
+        Some(it) => it.to_string(),
         None => {
-            let _ = stream.write_all(PAYLOAD_TOO_LARGE_RESPONSE).await;
-            Ok(())
+            ErrorPage::NotFound
+                .write(stream, site, headers, with_body)
+                .await;
+            return Ok(());
         }
-        Some(bytes) => {
-            if let Ok(req) = from_utf8(&bytes) {
-                LogLevel::Debug.log(|| println!("{}", req.dimmed()));
-            }
-            let bytes = bytes.as_slice();
-            if bytes.len() > 15 {
-                if let Some(pos) = bytes.windows(2).position(|p| p == b"\r\n") {
-                    if pos > 12 && &bytes[pos - 9..pos] == b" HTTP/1.1" {
-                        match bytes.get(pos + 1) {
-                            Some(b'\n') => match &bytes[0..4] {
-                                b"GET " => {
-                                    if let Ok(path) = from_utf8(&bytes[4..pos - 9]) {
-                                        LogLevel::Info.log(|| println!("{}", path));
-                                        let path = path.replace(CDN_ROOT.as_str(), "");
-                                        return match FILES.get(&path) {
-                                            None => {
-                                                let _ = stream.write_all(NOT_FOUND_RESPONSE).await;
-                                                Ok(())
-                                            }
-                                            Some(entry) => {
-                                                let not_modified = if let Some(etag) =
-                                                    get_if_none_match(&bytes[pos + 2..])
-                                                {
-                                                    &entry.etag == etag
-                                                } else {
-                                                    false
-                                                };
-                                                let _ = stream
-                                                    .write_all(if not_modified {
-                                                        &entry.not_modified
-                                                    } else {
-                                                        &entry.ok
-                                                    })
-                                                    .await;
-                                                Ok(())
-                                            }
-                                        };
-                                    }
-                                }
-                                _ => {
-                                    let _ = stream.write_all(METHOD_NOT_ALLOWED_RESPONSE).await;
-                                    return Ok(());
-                                }
-                            },
-                            _ => {}
+    };
+    let files = site.files.load_full();
+    let hashed = parse_hashed_path(site, &key).and_then(|(hash, key)| {
+        files
+            .get(&key)
+            .filter(|it| it.hash == hash && !it.is_index())
+            .cloned()
+    });
+    let (entry, cache_control) = match hashed {
+        // the content of a hashed path can never change
+        Some(entry) => (entry, IMMUTABLE.to_string()),
+        None => {
+            let entry = match files.get(&key).cloned() {
+                Some(entry) => entry,
+                None => {
+                    if serve_generated(stream, site, request, &key, with_body).await {
+                        return Ok(());
+                    }
+                    match spa_fallback(site, &key) {
+                        Some(entry) => entry,
+                        None => {
+                            ErrorPage::NotFound
+                                .write(stream, site, headers, with_body)
+                                .await;
+                            return Ok(());
                         }
                     }
                 }
+            };
+            let cache_control = entry.cache_control().to_string();
+            (entry, cache_control)
+        }
+    };
+    let encoding = negotiate(headers.get("accept-encoding"), &entry.encodings());
+    // If-Modified-Since is only considered when there is no If-None-Match
+    // The entity tag is the one of the representation that would be served.
+    let not_modified = match (
+        headers.get("if-none-match"),
+        headers.get("if-modified-since"),
+    ) {
+        (Some(if_none_match), _) => {
+            encoding.map_or(false, |it| none_match(if_none_match, &entry.etag(it)))
+        }
+        (None, Some(if_modified_since)) => {
+            not_modified_since(if_modified_since, entry.last_modified)
+        }
+        (None, None) => false,
+    };
+    // ranges are only honoured if the entity they were computed from hasn't changed since,
+    // and they always apply to the identity body
+    let ranges = match headers.get("if-range") {
+        Some(it) if !range_matches(it, &entry.etag(Encoding::Identity), entry.last_modified) => {
+            ByteRanges::Ignored
+        }
+        _ => parse_ranges(headers.get("range"), entry.identity_len()),
+    };
+    let origin = headers.get("origin");
+    if not_modified {
+        let encoding = encoding.unwrap_or(Encoding::Identity);
+        let _ = stream
+            .write_all(&entry.not_modified(&cache_control, encoding))
+            .await;
+    } else if let ByteRanges::Satisfiable(ranges) = ranges {
+        match entry.body(Encoding::Identity).await {
+            Ok(identity) => {
+                let _ = entry
+                    .write_partial(stream, identity, &ranges, &cache_control, origin, with_body)
+                    .await;
+            }
+            Err(err) => {
+                ErrorPage::for_error(&err)
+                    .write(stream, site, headers, with_body)
+                    .await
+            }
+        }
+    } else if ranges == ByteRanges::Unsatisfiable {
+        let _ = stream.write_all(&entry.range_not_satisfiable()).await;
+    } else if let Some(encoding) = encoding {
+        match entry.body(encoding).await {
+            Ok(body) => {
+                let _ = entry
+                    .write_full(stream, "200 OK", body, &cache_control, origin, with_body)
+                    .await;
+            }
+            Err(err) => {
+                ErrorPage::for_error(&err)
+                    .write(stream, site, headers, with_body)
+                    .await
             }
-            let _ = stream.write_all(BAD_REQUEST_RESPONSE).await;
-            Ok(())
         }
+    } else {
+        let _ = stream.write_all(NOT_ACCEPTABLE_RESPONSE).await;
     }
+    Ok(())
 }
 
-fn get_if_none_match(bytes: &[u8]) -> Option<&str> {
-    let mut bytes = bytes;
-    loop {
-        if let Some(pos) = bytes.iter().position(|p| *p == b'\r') {
-            match bytes.get(pos + 1) {
-                Some(b'\n') => {
-                    if pos == 0 {
-                        return None;
-                    }
-                    let header_line = &bytes[0..pos];
-                    if let Some(pos) = header_line.iter().position(|p| *p == b':') {
-                        if let Ok(header_name) =
-                            from_utf8(&header_line[0..pos]).map(|it| it.trim().to_lowercase())
-                        {
-                            match header_name.as_str() {
-                                "if-none-match" => {
-                                    return from_utf8(&header_line[pos + 2..])
-                                        .ok()
-                                        .map(|it| it.trim())
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    bytes = &bytes[pos + 1..];
-                }
-                _ => return None,
-            }
-        } else {
-            return None;
+// Serves the responses that don't come from a file: redirects to directories,
+// directory listings, and the generated documents.
+// Returns false if there is none for the key.
+async fn serve_generated(
+    stream: &mut TlsStream<TcpStream>,
+    site: &Site,
+    request: &Request,
+    key: &str,
+    with_body: bool,
+) -> bool {
+    if !key.ends_with('/') {
+        let dir = format!("{}/", key);
+        if site.files.load().contains_key(&dir) || listing(site, &dir).is_some() {
+            let query = request.target.split_once('?').map(|(_, query)| query);
+            let _ = stream
+                .write_all(&redirect(&dir_location(&dir, query)))
+                .await;
+            return true;
         }
     }
+    let (content_type, body) = if let Some(body) = listing(site, key) {
+        ("text/html; charset=utf-8", body)
+    } else if is_manifest(site, key) {
+        ("application/json", manifest(site))
+    } else if is_integrity_index(site, key) {
+        ("application/json", integrity_index(site))
+    } else {
+        return false;
+    };
+    // the generated documents are read by pages and workers of other origins, like the files
+    let policy = site
+        .rules
+        .policy(key)
+        .headers(request.headers.get("origin"));
+    let _ = stream
+        .write_all(&generated_header(content_type, body.len(), &policy))
+        .await;
+    if with_body {
+        let _ = stream.write_all(body.as_bytes()).await;
+    }
+    true
+}
+
+// The location is built from the normalized key rather than from the request target, so that
+// absolute-form targets or targets starting with // can't redirect to another host.
+fn dir_location(dir: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) => format!("{}?{}", percent_encode(dir), query),
+        None => percent_encode(dir),
+    }
+}
+
+fn redirect(location: &str) -> Vec<u8> {
+    format!(
+        "\
+HTTP/1.1 308 Permanent Redirect\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Location: {}\r\n\
+Content-Length: 0\r\n\
+\r\n",
+        location
+    )
+    .into_bytes()
+}
+
+// Generated documents reflect the current state of the cache and are never cached.
+fn generated_header(content_type: &str, len: usize, policy: &str) -> Vec<u8> {
+    format!(
+        "\
+HTTP/1.1 200 OK\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Content-Type: {}\r\n\
+Content-Length: {}\r\n\
+X-Content-Type-Options: nosniff\r\n\
+{}\
+Vary: Origin\r\n\
+\r\n",
+        content_type, len, policy
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::handler::{dir_location, generated_header};
+    use crate::cdn::path::UriPath;
+    use crate::cdn::policy::Rules;
+
+    fn location(target: &str) -> String {
+        let key = UriPath::parse("/", target).unwrap().to_string();
+        let query = target.split_once('?').map(|(_, query)| query);
+        dir_location(&format!("{}/", key), query)
+    }
+
+    #[test]
+    fn dir_locations() {
+        assert_eq!(location("/docs"), "/docs/");
+        assert_eq!(location("/docs?page=2"), "/docs/?page=2");
+        assert_eq!(location("/my%20docs"), "/my%20docs/");
+        assert_eq!(location("https://evil.example/docs"), "/docs/");
+        assert_eq!(location("//evil.example/docs"), "/evil.example/docs/");
+    }
+
+    #[test]
+    fn generated_headers() {
+        let policy = Rules::load(None)
+            .unwrap()
+            .policy("/asset-manifest.json")
+            .headers(Some("https://packurl.net"));
+        let header = String::from_utf8(generated_header("application/json", 2, &policy)).unwrap();
+        assert!(header.contains("\r\nAccess-Control-Allow-Origin: https://packurl.net\r\n"));
+        assert!(header.contains("\r\nCross-Origin-Resource-Policy: same-origin\r\n"));
+        assert!(header.ends_with("\r\nVary: Origin\r\n\r\n"));
+    }
 }
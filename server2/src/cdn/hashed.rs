@@ -0,0 +1,111 @@
+use crate::cdn::site::Site;
+use ring::digest::Digest;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Number of hex characters of the sha256 digest used in hashed paths.
+const HASH_LEN: usize = 16;
+const HASHED_DIR: &str = "h/";
+const MANIFEST: &str = "asset-manifest.json";
+
+/// Returns the hash used in the hashed path of a file with the given content digest.
+pub fn content_hash(digest: &Digest) -> String {
+    digest.as_ref()[..HASH_LEN / 2]
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect()
+}
+
+/// Returns the hashed path for the key: `/h/<hash>/app.mjs` for `/app.mjs`.
+pub fn hashed_path(prefix: &str, key: &str, hash: &str) -> Option<String> {
+    key.strip_prefix(prefix)
+        .filter(|it| !it.is_empty())
+        .map(|it| format!("{}{}{}/{}", prefix, HASHED_DIR, hash, it))
+}
+
+/// Splits a hashed path into the hash and the key of the file it designates.
+pub fn parse_hashed_path<'a>(site: &Site, path: &'a str) -> Option<(&'a str, String)> {
+    if site.hashed_paths {
+        split_hashed_path(&site.prefix, path)
+    } else {
+        None
+    }
+}
+
+fn split_hashed_path<'a>(prefix: &str, path: &'a str) -> Option<(&'a str, String)> {
+    let (hash, name) = path
+        .strip_prefix(prefix)?
+        .strip_prefix(HASHED_DIR)?
+        .split_once('/')?;
+    if hash.len() != HASH_LEN
+        || !hash
+            .bytes()
+            .all(|it| matches!(it, b'0'..=b'9' | b'a'..=b'f'))
+        || name.is_empty()
+    {
+        return None;
+    }
+    Some((hash, format!("{}{}", prefix, name)))
+}
+
+/// Returns true if the key is the one of the generated asset manifest.
+pub fn is_manifest(site: &Site, key: &str) -> bool {
+    site.hashed_paths && key.strip_prefix(site.prefix.as_str()) == Some(MANIFEST)
+}
+
+/// Generates the asset manifest, mapping each file to its hashed url and integrity value:
+/// `{ "/app.mjs": { "url": "/h/0123456789abcdef/app.mjs", "integrity": "sha384-..." } }`
+/// Directory indices aren't listed since relative urls in them would break under a hashed path.
+pub fn manifest(site: &Site) -> String {
+    let assets: BTreeMap<String, Value> = site
+        .files
+        .load()
+        .iter()
+        .filter(|(_, entry)| !entry.is_index())
+        .filter_map(|(key, entry)| {
+            let url = hashed_path(&site.prefix, key, &entry.hash)?;
+            Some((
+                key.to_string(),
+                json!({ "url": url, "integrity": entry.integrity }),
+            ))
+        })
+        .collect();
+    serde_json::to_string(&assets).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::hashed::{hashed_path, split_hashed_path, HASH_LEN};
+
+    #[test]
+    fn hashed_paths() {
+        let hash = "0123456789abcdef";
+        assert_eq!(hash.len(), HASH_LEN);
+        assert_eq!(
+            hashed_path("/", "/js/app.mjs", hash).as_deref(),
+            Some("/h/0123456789abcdef/js/app.mjs")
+        );
+        assert_eq!(
+            hashed_path("/cdn/", "/cdn/app.mjs", hash).as_deref(),
+            Some("/cdn/h/0123456789abcdef/app.mjs")
+        );
+        assert_eq!(hashed_path("/", "/", hash), None);
+    }
+
+    #[test]
+    fn parse_hashed_paths() {
+        assert_eq!(
+            split_hashed_path("/", "/h/0123456789abcdef/js/app.mjs"),
+            Some(("0123456789abcdef", "/js/app.mjs".to_string()))
+        );
+        assert_eq!(
+            split_hashed_path("/cdn/", "/cdn/h/0123456789abcdef/app.mjs"),
+            Some(("0123456789abcdef", "/cdn/app.mjs".to_string()))
+        );
+        assert_eq!(split_hashed_path("/", "/h/0123456789abcdef"), None);
+        assert_eq!(split_hashed_path("/", "/h/0123456789abcdef/"), None);
+        assert_eq!(split_hashed_path("/", "/h/0123456789ABCDEF/app.mjs"), None);
+        assert_eq!(split_hashed_path("/", "/h/0123/app.mjs"), None);
+        assert_eq!(split_hashed_path("/", "/js/app.mjs"), None);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::cdn::site::Site;
+use ring::digest::Digest;
+use std::collections::BTreeMap;
+
+/// Returns the Subresource Integrity value for the sha384 digest of a file.
+pub fn sri(digest: Digest) -> String {
+    format!("sha384-{}", base64::encode(digest))
+}
+
+/// Returns true if the key is the one of the generated integrity index.
+pub fn is_integrity_index(site: &Site, key: &str) -> bool {
+    site.integrity_index.as_ref().map_or(false, |it| {
+        key.strip_prefix(site.prefix.as_str()) == Some(it.as_str())
+    })
+}
+
+/// Generates the integrity index, mapping every file to its integrity value:
+/// `{ "/app.mjs": "sha384-..." }`
+pub fn integrity_index(site: &Site) -> String {
+    let index: BTreeMap<String, String> = site
+        .files
+        .load()
+        .iter()
+        .map(|(key, entry)| (key.to_string(), entry.integrity.clone()))
+        .collect();
+    serde_json::to_string(&index).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::cache::Cache;
+    use crate::cdn::integrity::{integrity_index, sri};
+    use crate::cdn::site::Site;
+    use ring::digest::{digest, SHA384};
+    use std::fs::write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn sri_value() {
+        assert_eq!(
+            sri(digest(&SHA384, b"alert('Hello, world.');")),
+            "sha384-H8BRh8j48O9oYatfu5AZzq6A9RINhZO5H16dQZngK7T62em8MUt1FLm52t+eX6xO"
+        );
+    }
+
+    #[tokio::test]
+    async fn index() {
+        let temp = tempdir().unwrap();
+        write(temp.path().join("a.png"), "a").unwrap();
+        write(temp.path().join("b.png"), "bb").unwrap();
+        let site: &'static Site = Site::for_test(temp.path());
+        Cache::update_site(site).await.unwrap();
+        assert_eq!(
+            integrity_index(site),
+            "{\
+\"/a.png\":\"sha384-VKWbnyKwuAiA2EJ+VIt8I6vYc0huHwNdzpzWl+hRdQM8qojm1XvDXvrgta/TFF8x\",\
+\"/b.png\":\"sha384-VwnAEFDH9u2FWSw4FaFO0EYSrn+QS1thD9xlKShXIBaAlu2N484rsX98Sv4Whs/o\"\
+}"
+        );
+    }
+}
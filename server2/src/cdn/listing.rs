@@ -0,0 +1,85 @@
+use crate::cdn::path::percent_encode;
+use crate::cdn::site::Site;
+use std::collections::BTreeSet;
+
+/// Returns the html listing of the directory (the key ends with a slash),
+/// or None if listings aren't enabled for it or if it doesn't contain any file.
+pub fn listing(site: &Site, dir: &str) -> Option<String> {
+    if !site.listings.iter().any(|it| dir.starts_with(it.as_str())) {
+        return None;
+    }
+    let names = children(dir, site.files.load().keys().cloned());
+    if names.is_empty() {
+        return None;
+    }
+    let items: String = names
+        .iter()
+        .map(|it| {
+            format!(
+                "<li><a href=\"./{}\">{}</a></li>\n",
+                percent_encode(it),
+                escape(it)
+            )
+        })
+        .collect();
+    Some(format!(
+        "\
+<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{0}</title>
+</head>
+<body>
+<h1>{0}</h1>
+<ul>
+{1}</ul>
+</body>
+</html>
+",
+        escape(dir),
+        items
+    ))
+}
+
+// Returns the names of the files and subdirectories (with a trailing slash) directly under dir.
+fn children(dir: &str, keys: impl Iterator<Item = String>) -> BTreeSet<String> {
+    keys.filter_map(|key| {
+        let rest = key.strip_prefix(dir)?;
+        match rest.split_once('/') {
+            Some((name, _)) if !name.is_empty() => Some(format!("{}/", name)),
+            None if !rest.is_empty() => Some(rest.to_string()),
+            _ => None,
+        }
+    })
+    .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::listing::children;
+
+    #[test]
+    fn directory_children() {
+        let keys = [
+            "/docs/",
+            "/docs/a.html",
+            "/docs/img/logo.svg",
+            "/docs/img/",
+            "/docs/b.css",
+            "/docsx/c.css",
+            "/app.mjs",
+        ];
+        let names: Vec<String> = children("/docs/", keys.iter().map(|it| it.to_string()))
+            .into_iter()
+            .collect();
+        assert_eq!(names, vec!["a.html", "b.css", "img/"]);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::cdn::cache::Files;
+use crate::cdn::site::Site;
+use arc_swap::ArcSwapOption;
+use lazy_static::lazy_static;
+use std::env::var;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+lazy_static! {
+    static ref BUDGET: Budget = Budget::new(
+        var("XDG_WWW_MEMORY_BUDGET")
+            .ok()
+            .and_then(|it| it.parse().ok())
+            .unwrap_or(256 * 1024 * 1024)
+    );
+    /// Files larger than this are never held in memory and are streamed from disk instead.
+    pub static ref STREAM_THRESHOLD: u64 = var("XDG_WWW_STREAM_THRESHOLD")
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(4 * 1024 * 1024);
+}
+
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+static STREAMED: AtomicU64 = AtomicU64::new(0);
+
+/// The response bodies of a file, for each available encoding.
+pub struct Bodies {
+    pub identity: Vec<u8>,
+    pub br: Option<Vec<u8>>,
+    pub gzip: Option<Vec<u8>>,
+}
+
+impl Bodies {
+    fn size(&self) -> usize {
+        self.identity.len()
+            + self.br.as_ref().map_or(0, |it| it.len())
+            + self.gzip.as_ref().map_or(0, |it| it.len())
+    }
+}
+
+/// The memory limit for the bodies held in memory, and how much of it is used.
+struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl Budget {
+    fn new(limit: usize) -> Self {
+        Budget {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Evicts the least recently used of the given bodies when the memory used exceeds the limit.
+    /// Evictions are done in batches, down to a low-water mark, so that the entries are only
+    /// collected and sorted once in a while rather than on every reload.
+    fn enforce<'a>(&self, residents: impl Iterator<Item = &'a Resident>) {
+        if self.used() <= self.limit {
+            return;
+        }
+        let low_water = self.limit / 4 * 3;
+        let mut residents: Vec<(u64, &Resident)> = residents
+            .filter(|it| it.is_loaded())
+            .map(|it| (it.last_used.load(Ordering::Relaxed), it))
+            .collect();
+        residents.sort_by_key(|(last_used, _)| *last_used);
+        for (_, resident) in residents {
+            if self.used() <= low_water {
+                break;
+            }
+            resident.evict();
+        }
+    }
+}
+
+/// Holds the bodies of a file in memory until they are evicted to stay within the budget.
+pub struct Resident {
+    budget: &'static Budget,
+    bodies: ArcSwapOption<Bodies>,
+    last_used: AtomicU64,
+}
+
+impl Resident {
+    pub fn new(bodies: Bodies) -> Self {
+        Self::within(&BUDGET, bodies)
+    }
+
+    // The bodies of new entries are only kept if they fit, so that building a snapshot never
+    // holds more than the budget: the others are loaded when they are first requested.
+    fn within(budget: &'static Budget, bodies: Bodies) -> Self {
+        let resident = Resident {
+            budget,
+            bodies: ArcSwapOption::empty(),
+            last_used: AtomicU64::new(0),
+        };
+        if budget.used() + bodies.size() <= budget.limit {
+            resident.store(bodies);
+        }
+        resident
+    }
+
+    /// Returns the bodies if they are still in memory.
+    pub fn get(&self) -> Option<Arc<Bodies>> {
+        match self.bodies.load_full() {
+            Some(bodies) => {
+                self.touch();
+                HITS.fetch_add(1, Ordering::Relaxed);
+                Some(bodies)
+            }
+            None => {
+                MISSES.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, bodies: Bodies) -> Arc<Bodies> {
+        let bodies = Arc::new(bodies);
+        self.budget.used.fetch_add(bodies.size(), Ordering::Relaxed);
+        if let Some(previous) = self.bodies.swap(Some(bodies.clone())) {
+            self.budget
+                .used
+                .fetch_sub(previous.size(), Ordering::Relaxed);
+        }
+        self.touch();
+        bodies
+    }
+
+    fn evict(&self) {
+        if let Some(previous) = self.bodies.swap(None) {
+            self.budget
+                .used
+                .fetch_sub(previous.size(), Ordering::Relaxed);
+            EVICTIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        self.bodies.load().is_some()
+    }
+
+    fn touch(&self) {
+        self.last_used
+            .store(CLOCK.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Resident {
+    fn drop(&mut self) {
+        if let Some(bodies) = self.bodies.swap(None) {
+            self.budget.used.fetch_sub(bodies.size(), Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn count_streamed() {
+    STREAMED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Evicts the least recently used bodies of the current cache entries of all the sites when the
+/// memory used exceeds the budget.
+pub fn enforce_budget() {
+    if BUDGET.used() <= BUDGET.limit {
+        return;
+    }
+    let files: Vec<Arc<Files>> = Site::all().iter().map(|it| it.files.load_full()).collect();
+    BUDGET.enforce(
+        files
+            .iter()
+            .flat_map(|it| it.values())
+            .filter_map(|it| it.resident()),
+    );
+}
+
+/// Returns a text report of the cache usage.
+pub fn stats() -> String {
+    let (mut entries, mut resident, mut streamed) = (0, 0, 0);
+    for site in Site::all() {
+        let files = site.files.load();
+        entries += files.len();
+        for entry in files.values() {
+            match entry.resident() {
+                Some(it) if it.is_loaded() => resident += 1,
+                Some(_) => {}
+                None => streamed += 1,
+            }
+        }
+    }
+    format!(
+        "\
+entries: {}\n\
+resident: {}\n\
+streamed: {}\n\
+memory: {} / {} bytes\n\
+hits: {}\n\
+misses: {}\n\
+evictions: {}\n\
+streamed responses: {}\n",
+        entries,
+        resident,
+        streamed,
+        BUDGET.used(),
+        BUDGET.limit,
+        HITS.load(Ordering::Relaxed),
+        MISSES.load(Ordering::Relaxed),
+        EVICTIONS.load(Ordering::Relaxed),
+        STREAMED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::memory::{Bodies, Budget, Resident};
+
+    fn bodies(len: usize) -> Bodies {
+        Bodies {
+            identity: vec![0; len],
+            br: None,
+            gzip: None,
+        }
+    }
+
+    #[test]
+    fn budget() {
+        let budget: &'static Budget = Box::leak(Box::new(Budget::new(100)));
+        let a = Resident::within(budget, bodies(40));
+        let b = Resident::within(budget, bodies(40));
+        // new entries that don't fit are left on disk
+        let c = Resident::within(budget, bodies(40));
+        assert!(a.is_loaded() && b.is_loaded() && !c.is_loaded());
+        assert_eq!(budget.used(), 80);
+
+        // reloads can go over budget until it is enforced
+        c.store(bodies(40));
+        assert!(a.get().is_some());
+        assert_eq!(budget.used(), 120);
+        budget.enforce([&a, &b, &c].into_iter());
+        // evicted from the least recently used, down to the low-water mark (75)
+        assert!(a.is_loaded() && !b.is_loaded() && !c.is_loaded());
+        assert_eq!(budget.used(), 40);
+
+        drop(a);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::log::LogLevel;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env::var;
+use std::fs::read;
+use std::io::{Error, ErrorKind, Result};
+
+pub const IMMUTABLE: &str = "public,max-age=31536000,immutable";
+const NO_CACHE: &str = "public,no-cache";
+const REVALIDATE: &str = "public,max-age=3600,must-revalidate";
+
+const DEFAULTS: &[(&str, &str, &str)] = &[
+    ("html", "text/html", NO_CACHE),
+    ("htm", "text/html", NO_CACHE),
+    ("css", "text/css", NO_CACHE),
+    ("js", "application/javascript", NO_CACHE),
+    ("mjs", "application/javascript", NO_CACHE),
+    ("js.map", "application/json", NO_CACHE),
+    ("mjs.map", "application/json", NO_CACHE),
+    ("json", "application/json", REVALIDATE),
+    ("webmanifest", "application/manifest+json", REVALIDATE),
+    ("xml", "application/xml", REVALIDATE),
+    ("md", "text/markdown", NO_CACHE),
+    ("txt", "text/plain", NO_CACHE),
+    ("glsl", "text/plain", NO_CACHE),
+    ("wat", "text/plain", NO_CACHE),
+    ("svg", "image/svg+xml", IMMUTABLE),
+    ("jpg", "image/jpeg", IMMUTABLE),
+    ("jpeg", "image/jpeg", IMMUTABLE),
+    ("png", "image/png", IMMUTABLE),
+    ("gif", "image/gif", IMMUTABLE),
+    ("webp", "image/webp", IMMUTABLE),
+    ("avif", "image/avif", IMMUTABLE),
+    ("ico", "image/x-icon", IMMUTABLE),
+    ("woff2", "font/woff2", IMMUTABLE),
+    ("woff", "font/woff", IMMUTABLE),
+    ("ttf", "font/ttf", IMMUTABLE),
+    ("otf", "font/otf", IMMUTABLE),
+    ("wasm", "application/wasm", IMMUTABLE),
+    ("glb", "model/gltf-binary", IMMUTABLE),
+    ("gltf", "model/gltf+json", IMMUTABLE),
+    ("mp3", "audio/mpeg", IMMUTABLE),
+    ("wav", "audio/wav", IMMUTABLE),
+    ("ogg", "audio/ogg", IMMUTABLE),
+    ("mp4", "video/mp4", IMMUTABLE),
+    ("webm", "video/webm", IMMUTABLE),
+    ("pdf", "application/pdf", IMMUTABLE),
+    ("zip", "application/zip", IMMUTABLE),
+    ("sig", "application/pgp-signature", "no-store"),
+];
+
+lazy_static! {
+    static ref MIME_FILE: Option<String> = var("XDG_WWW_MIME_TYPES").ok();
+    static ref CHARSET: Option<String> =
+        Some(var("XDG_WWW_CHARSET").unwrap_or("utf-8".to_string())).filter(|it| !it.is_empty());
+    static ref FALLBACK: Option<MimeType> = match var("XDG_WWW_MIME_FALLBACK") {
+        Ok(it) if !it.is_empty() && it != "skip" => Some(MimeType::new(&it, NO_CACHE)),
+        _ => None,
+    };
+    static ref TYPES: std::result::Result<HashMap<String, MimeType>, String> =
+        load_types().map_err(|err| err.to_string());
+}
+
+pub struct MimeType {
+    pub content_type: String,
+    pub cache_control: String,
+}
+
+impl MimeType {
+    fn new(content_type: &str, cache_control: &str) -> Self {
+        let content_type = match CHARSET.as_ref() {
+            Some(charset) if has_charset(content_type) => {
+                format!("{}; charset={}", content_type, charset)
+            }
+            _ => content_type.to_string(),
+        };
+        MimeType {
+            content_type,
+            cache_control: cache_control.to_string(),
+        }
+    }
+
+    /// Checks the MIME types configuration, so that errors are reported at startup.
+    pub fn validate() -> Result<()> {
+        match TYPES.as_ref() {
+            Ok(types) => {
+                if let Some(path) = MIME_FILE.as_ref() {
+                    LogLevel::Info.log(|| {
+                        println!("Loaded {} MIME types from {}", types.len(), path.yellow())
+                    });
+                }
+                Ok(())
+            }
+            Err(err) => Err(Error::new(ErrorKind::InvalidInput, err.clone())),
+        }
+    }
+
+    /// Returns the type for the file name, using the longest matching extension (ignoring case),
+    /// or the fallback type if there is one.
+    /// Files without a type aren't served. The fallback doesn't apply to the precompressed
+    /// siblings of other files, which are served as encodings of these files.
+    pub fn for_filename(filename: &str) -> Option<&'static MimeType> {
+        let types = TYPES.as_ref().ok()?;
+        filename
+            .char_indices()
+            .skip(1)
+            .filter(|(_, it)| *it == '.')
+            .find_map(|(i, _)| types.get(&filename[i + 1..].to_lowercase()))
+            .or_else(|| {
+                if is_compressed_sibling(filename) {
+                    None
+                } else {
+                    FALLBACK.as_ref()
+                }
+            })
+    }
+}
+
+fn is_compressed_sibling(filename: &str) -> bool {
+    filename
+        .rsplit_once('.')
+        .map_or(false, |(name, extension)| {
+            !name.is_empty()
+                && (extension.eq_ignore_ascii_case("br") || extension.eq_ignore_ascii_case("gz"))
+        })
+}
+
+// Text types are served with a charset, unless they define their own encoding.
+fn has_charset(content_type: &str) -> bool {
+    !content_type.contains(';')
+        && (content_type.starts_with("text/")
+            || matches!(
+                content_type,
+                "application/javascript"
+                    | "application/json"
+                    | "application/manifest+json"
+                    | "application/xml"
+                    | "image/svg+xml"
+            ))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfiguredType {
+    content_type: String,
+    cache_control: String,
+}
+
+/// Loads the default types, overridden by the ones in the configuration file.
+/// The file is a json object with extensions as keys, and null to stop serving an extension:
+/// `{ "avif": { "content-type": "image/avif", "cache-control": "no-cache" }, "md": null }`
+fn load_types() -> Result<HashMap<String, MimeType>> {
+    let mut types: HashMap<String, MimeType> = DEFAULTS
+        .iter()
+        .map(|(extension, content_type, cache_control)| {
+            (
+                extension.to_string(),
+                MimeType::new(content_type, cache_control),
+            )
+        })
+        .collect();
+    if let Some(path) = MIME_FILE.as_ref() {
+        let configured: HashMap<String, Option<ConfiguredType>> =
+            serde_json::from_slice(&read(path)?).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid MIME types {}: {}", path, err),
+                )
+            })?;
+        for (extension, it) in configured {
+            let extension = extension.trim_start_matches('.').to_lowercase();
+            match it {
+                None => {
+                    types.remove(&extension);
+                }
+                Some(it) => {
+                    if extension.is_empty()
+                        || !is_valid_content_type(&it.content_type)
+                        || it.cache_control.bytes().any(|it| it.is_ascii_control())
+                    {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid MIME type for .{}", extension),
+                        ));
+                    }
+                    types.insert(
+                        extension,
+                        MimeType::new(&it.content_type, &it.cache_control),
+                    );
+                }
+            }
+        }
+    }
+    if let Some(fallback) = FALLBACK.as_ref() {
+        if !is_valid_content_type(&fallback.content_type) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid fallback MIME type {}", fallback.content_type),
+            ));
+        }
+    }
+    Ok(types)
+}
+
+fn is_valid_content_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) => {
+            let token = |it: &str| {
+                !it.is_empty()
+                    && it
+                        .bytes()
+                        .all(|it| it.is_ascii_alphanumeric() || b"!#$&-^_.+".contains(&it))
+            };
+            token(kind) && token(subtype) && !content_type.bytes().any(|it| it.is_ascii_control())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::mime::{is_compressed_sibling, MimeType};
+
+    fn content_type(filename: &str) -> Option<&str> {
+        MimeType::for_filename(filename).map(|it| it.content_type.as_str())
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(content_type("index.html"), Some("text/html; charset=utf-8"));
+        assert_eq!(
+            content_type("app.mjs.map"),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(
+            content_type("jquery.min.js"),
+            Some("application/javascript; charset=utf-8")
+        );
+        assert_eq!(content_type("clip.mp4"), Some("video/mp4"));
+        assert_eq!(content_type("photo.avif"), Some("image/avif"));
+        assert_eq!(
+            content_type("Logo.SVG"),
+            Some("image/svg+xml; charset=utf-8")
+        );
+        assert_eq!(content_type("archive.tar"), None);
+        assert_eq!(content_type("html"), None);
+    }
+
+    #[test]
+    fn compressed_siblings() {
+        assert!(is_compressed_sibling("app.mjs.br"));
+        assert!(is_compressed_sibling("app.mjs.gz"));
+        assert!(is_compressed_sibling("APP.MJS.GZ"));
+        assert!(!is_compressed_sibling("app.mjs"));
+        assert!(!is_compressed_sibling(".br"));
+        assert!(!is_compressed_sibling("brochure.pdf"));
+    }
+}
@@ -1,6 +1,23 @@
 pub use cache::Cache;
 pub use handler::handle_cdn_request;
+pub use memory::stats;
+pub use site::{Site, ROOT};
 
 mod cache;
+mod compress;
+mod conditional;
+mod cors;
+mod encoding;
 mod handler;
-mod path;
\ No newline at end of file
+mod hashed;
+mod integrity;
+mod listing;
+mod memory;
+mod mime;
+mod pages;
+mod path;
+mod policy;
+mod range;
+mod site;
+mod visit;
+mod watch;
\ No newline at end of file
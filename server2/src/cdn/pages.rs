@@ -0,0 +1,131 @@
+use crate::cdn::cache::FileEntry;
+use crate::cdn::encoding::negotiate;
+use crate::cdn::site::Site;
+use crate::http::Headers;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+const NOT_FOUND_RESPONSE: &[u8] = b"HTTP/1.1 404 Not Found\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Content-Length: 0\r\n\
+\r\n";
+const SERVER_ERROR_RESPONSE: &[u8] = b"HTTP/1.1 500 Internal Server Error\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Content-Length: 0\r\n\
+\r\n";
+
+/// Error responses, using the `404.html` and `50x.html` pages of the web root when they exist.
+/// Errors detected while reading the request (400, 413, ...) keep their empty responses:
+/// the request head isn't available to negotiate the encoding of a page, and is likely
+/// not coming from a browser anyway.
+pub enum ErrorPage {
+    NotFound,
+    ServerError,
+}
+
+impl ErrorPage {
+    /// Returns the page for an error that occurred while loading a file.
+    /// NotFound means that the file was removed or changed since the cache was last updated.
+    pub fn for_error(err: &Error) -> Self {
+        if err.kind() == ErrorKind::NotFound {
+            ErrorPage::NotFound
+        } else {
+            ErrorPage::ServerError
+        }
+    }
+
+    fn filename(&self) -> &'static str {
+        match self {
+            ErrorPage::NotFound => "404.html",
+            ErrorPage::ServerError => "50x.html",
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match self {
+            ErrorPage::NotFound => "404 Not Found",
+            ErrorPage::ServerError => "500 Internal Server Error",
+        }
+    }
+
+    fn empty_response(&self) -> &'static [u8] {
+        match self {
+            ErrorPage::NotFound => NOT_FOUND_RESPONSE,
+            ErrorPage::ServerError => SERVER_ERROR_RESPONSE,
+        }
+    }
+
+    /// Writes the error response, with the custom page as the body if there is one.
+    pub async fn write<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        site: &Site,
+        headers: &Headers,
+        with_body: bool,
+    ) {
+        let entry = site
+            .files
+            .load()
+            .get(&format!("{}{}", site.prefix, self.filename()))
+            .cloned();
+        if let Some(entry) = entry {
+            if let Some(encoding) = negotiate(headers.get("accept-encoding"), &entry.encodings()) {
+                if let Ok(body) = entry.body(encoding).await {
+                    let _ = entry
+                        .write_full(
+                            stream,
+                            self.status(),
+                            body,
+                            "no-cache",
+                            headers.get("origin"),
+                            with_body,
+                        )
+                        .await;
+                    return;
+                }
+            }
+        }
+        let _ = stream.write_all(self.empty_response()).await;
+    }
+}
+
+/// Returns the index of the single page app for unknown paths under its prefix, so that
+/// the app can handle its own routes.
+/// Paths with an extension are assumed to be missing assets rather than app routes.
+pub fn spa_fallback(site: &Site, key: &str) -> Option<Arc<FileEntry>> {
+    let spa_prefix = site.spa_prefix.as_ref()?;
+    if !is_spa_route(spa_prefix, key) {
+        return None;
+    }
+    site.files.load().get(spa_prefix).cloned()
+}
+
+fn is_spa_route(spa_prefix: &str, key: &str) -> bool {
+    key.strip_prefix(spa_prefix).map_or(false, |route| {
+        !route
+            .rsplit('/')
+            .next()
+            .map_or(false, |it| it.contains('.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::pages::is_spa_route;
+
+    #[test]
+    fn spa_routes() {
+        assert!(is_spa_route("/app/", "/app/"));
+        assert!(is_spa_route("/app/", "/app/settings"));
+        assert!(is_spa_route("/app/", "/app/users/42/"));
+        assert!(is_spa_route("/app/", "/app/v1.2/users"));
+        assert!(!is_spa_route("/app/", "/app/logo.svg"));
+        assert!(!is_spa_route("/app/", "/app/js/main.mjs"));
+        assert!(!is_spa_route("/app/", "/docs/settings"));
+        assert!(!is_spa_route("/app/", "/app"));
+        assert!(!is_spa_route("/app/", "/application"));
+    }
+}
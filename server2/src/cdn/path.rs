@@ -1,13 +1,21 @@
 use std::path::Path;
+use std::str::from_utf8;
 
 pub struct UriPath<'a> {
     prefix: &'a str,
     components: Vec<String>,
+    /// Directory keys end with a slash.
+    dir: bool,
 }
 
 impl ToString for UriPath<'_> {
     fn to_string(&self) -> String {
-        format!("{}{}", self.prefix, self.components.join("/"))
+        let path = format!("{}{}", self.prefix, self.components.join("/"));
+        if self.dir && !path.ends_with('/') {
+            format!("{}/", path)
+        } else {
+            path
+        }
     }
 }
 
@@ -18,7 +26,11 @@ impl<'a> UriPath<'a> {
             .filter(|it| *it != "." && *it != "")
             .map(|it| it.to_string())
             .collect();
-        UriPath { prefix, components }
+        UriPath {
+            prefix,
+            components,
+            dir: false,
+        }
     }
     pub fn join(&'a self, component: &'_ str) -> Self {
         let mut components = self.components.to_vec();
@@ -26,6 +38,7 @@ impl<'a> UriPath<'a> {
         UriPath {
             prefix: self.prefix,
             components,
+            dir: false,
         }
     }
     pub fn parent(&'a self) -> Option<Self> {
@@ -34,9 +47,15 @@ impl<'a> UriPath<'a> {
             Some(UriPath {
                 prefix: self.prefix,
                 components,
+                dir: false,
             })
         })
     }
+    /// Returns the same path as a directory.
+    pub fn into_dir(mut self) -> Self {
+        self.dir = true;
+        self
+    }
     pub fn from(prefix: &'a str, root: &'_ str, path: &'_ Path) -> Option<Self> {
         path.to_str().and_then(|path| {
             if !path.starts_with(root) {
@@ -45,4 +64,134 @@ impl<'a> UriPath<'a> {
             Some(Self::new(prefix, &path[root.len()..]))
         })
     }
+
+    /// Parses a request target into the key used by the cache.
+    /// The scheme and authority of absolute-form targets, the query and the fragment are ignored,
+    /// empty and `.` segments are dropped, and the remaining segments are percent-decoded.
+    /// Targets ending with a slash designate directories.
+    /// Returns None for targets outside the prefix, with `..` segments or with invalid encoding.
+    pub fn parse(prefix: &'a str, target: &'_ str) -> Option<Self> {
+        let target = target.split(['?', '#']).next()?;
+        let target = match target
+            .strip_prefix("https://")
+            .or_else(|| target.strip_prefix("http://"))
+        {
+            Some(it) => &it[it.find('/')?..],
+            None => target,
+        };
+        if !target.starts_with('/') {
+            return None;
+        }
+        let target = target.strip_prefix(prefix.trim_end_matches('/'))?;
+        if !target.is_empty() && !target.starts_with('/') {
+            return None;
+        }
+        let mut components = Vec::new();
+        for segment in target.split('/').filter(|it| *it != "." && !it.is_empty()) {
+            let segment = percent_decode(segment)?;
+            if segment == ".." || segment.contains(['/', '\\', '\0']) {
+                return None;
+            }
+            if segment != "." && !segment.is_empty() {
+                components.push(segment);
+            }
+        }
+        let dir = matches!(target.rsplit('/').next(), Some("" | "."));
+        Some(UriPath {
+            prefix,
+            components,
+            dir,
+        })
+    }
+}
+
+/// Percent-encodes everything but the unreserved characters and the slashes of a path.
+pub fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|it| match it {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (it as char).to_string()
+            }
+            _ => format!("%{:02X}", it),
+        })
+        .collect()
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            // from_str_radix alone would accept a leading sign
+            if !hex.iter().all(|it| it.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::path::{percent_encode, UriPath};
+
+    fn key(prefix: &str, target: &str) -> Option<String> {
+        UriPath::parse(prefix, target).map(|it| it.to_string())
+    }
+
+    #[test]
+    fn normalization() {
+        assert_eq!(key("/", "/"), Some("/".to_string()));
+        assert_eq!(key("/", "/app.mjs?v=3"), Some("/app.mjs".to_string()));
+        assert_eq!(key("/", "/app.mjs#top"), Some("/app.mjs".to_string()));
+        assert_eq!(
+            key("/", "//a/./b//app.mjs"),
+            Some("/a/b/app.mjs".to_string())
+        );
+        assert_eq!(key("/", "/dir/"), Some("/dir/".to_string()));
+        assert_eq!(key("/", "/dir/."), Some("/dir/".to_string()));
+        assert_eq!(key("/", "/dir"), Some("/dir".to_string()));
+        assert_eq!(key("/", "/my%20file.css"), Some("/my file.css".to_string()));
+        assert_eq!(key("/", "/caf%C3%A9.txt"), Some("/café.txt".to_string()));
+        assert_eq!(key("/", "/Logo.SVG"), Some("/Logo.SVG".to_string()));
+        assert_eq!(
+            key("/", "https://cdn.packurl.net/app.mjs"),
+            Some("/app.mjs".to_string())
+        );
+        assert_eq!(
+            key("/www/", "/www/app.mjs"),
+            Some("/www/app.mjs".to_string())
+        );
+        assert_eq!(key("/www/", "/www"), Some("/www/".to_string()));
+    }
+
+    #[test]
+    fn rejection() {
+        assert_eq!(key("/", "*"), None);
+        assert_eq!(key("/", "app.mjs"), None);
+        assert_eq!(key("/", "/../etc/passwd"), None);
+        assert_eq!(key("/", "/a/%2e%2e/b"), None);
+        assert_eq!(key("/", "/a%2fb"), None);
+        assert_eq!(key("/", "/a%2"), None);
+        assert_eq!(key("/", "/%ff"), None);
+        assert_eq!(key("/", "/%+1"), None);
+        assert_eq!(key("/", "/%-1"), None);
+        assert_eq!(key("/", "https://cdn.packurl.net"), None);
+        assert_eq!(key("/www/", "/wwwx/app.mjs"), None);
+        assert_eq!(key("/www/", "/app.mjs"), None);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(percent_encode("/a/b-c_d~e.txt"), "/a/b-c_d~e.txt");
+        assert_eq!(percent_encode("/my file?.css"), "/my%20file%3F.css");
+        assert_eq!(percent_encode("/café"), "/caf%C3%A9");
+    }
 }
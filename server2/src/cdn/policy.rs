@@ -0,0 +1,405 @@
+use crate::cdn::cors::allowed_origin;
+use crate::log::LogLevel;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::env::var;
+use std::fs::read;
+use std::io::{Error, ErrorKind, Result};
+
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self' 'unsafe-inline'; worker-src 'self'; frame-src 'none'; object-src 'none'; base-uri 'none'; frame-ancestors 'none'";
+const DEFAULT_STRICT_TRANSPORT_SECURITY: &str = "max-age=63072000; includeSubDomains; preload";
+
+lazy_static! {
+    static ref DEFAULT_ORIGINS: Vec<String> = var("XDG_CORS_ORIGINS")
+        .unwrap_or("https://packurl.net".to_string())
+        .split(',')
+        .map(|it| it.trim().to_string())
+        .filter(|it| !it.is_empty())
+        .collect();
+}
+
+/// A set of header values applied to the paths matching a glob.
+/// Missing fields are inherited from the previous matching rules (or the defaults),
+/// and an empty value removes the header.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Rule {
+    path: String,
+    content_security_policy: Option<String>,
+    cors_origins: Option<Vec<String>>,
+    cross_origin_embedder_policy: Option<String>,
+    cross_origin_resource_policy: Option<String>,
+    cross_origin_opener_policy: Option<String>,
+    strict_transport_security: Option<String>,
+    permissions_policy: Option<String>,
+    x_frame_options: Option<String>,
+}
+
+/// The headers that apply to a path, after merging all the matching rules.
+pub struct Policy {
+    content_security_policy: Option<String>,
+    pub cors_origins: Vec<String>,
+    cross_origin_embedder_policy: Option<String>,
+    cross_origin_resource_policy: Option<String>,
+    cross_origin_opener_policy: Option<String>,
+    strict_transport_security: Option<String>,
+    permissions_policy: Option<String>,
+    x_frame_options: Option<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            content_security_policy: Some(DEFAULT_CONTENT_SECURITY_POLICY.to_string()),
+            cors_origins: DEFAULT_ORIGINS.clone(),
+            cross_origin_embedder_policy: Some("require-corp".to_string()),
+            cross_origin_resource_policy: Some("same-origin".to_string()),
+            cross_origin_opener_policy: None,
+            strict_transport_security: Some(DEFAULT_STRICT_TRANSPORT_SECURITY.to_string()),
+            permissions_policy: None,
+            x_frame_options: None,
+        }
+    }
+}
+
+/// The header rules of a site, loaded from a json array of rules.
+#[derive(Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// Loads and checks the rules of the policy file, if there is one.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        validate_origins(&DEFAULT_ORIGINS)?;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Rules::default()),
+        };
+        let rules: Vec<Rule> = serde_json::from_slice(&read(path)?).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid header policy {}: {}", path, err),
+            )
+        })?;
+        for rule in rules.iter() {
+            validate_rule(rule).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid header rule for {}: {}", rule.path, err),
+                )
+            })?;
+        }
+        LogLevel::Info
+            .log(|| println!("Loaded {} header rules from {}", rules.len(), path.yellow()));
+        Ok(Rules(rules))
+    }
+
+    /// Returns the policy for the given url path (a cache key).
+    pub fn policy(&self, path: &str) -> Policy {
+        let mut policy = Policy::default();
+        let merge = |value: &Option<String>, target: &mut Option<String>| {
+            if let Some(value) = value {
+                *target = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.clone())
+                };
+            }
+        };
+        for rule in self.0.iter().filter(|it| glob_matches(&it.path, path)) {
+            merge(
+                &rule.content_security_policy,
+                &mut policy.content_security_policy,
+            );
+            if let Some(origins) = rule.cors_origins.as_ref() {
+                policy.cors_origins = origins.clone();
+            }
+            merge(
+                &rule.cross_origin_embedder_policy,
+                &mut policy.cross_origin_embedder_policy,
+            );
+            merge(
+                &rule.cross_origin_resource_policy,
+                &mut policy.cross_origin_resource_policy,
+            );
+            merge(
+                &rule.cross_origin_opener_policy,
+                &mut policy.cross_origin_opener_policy,
+            );
+            merge(
+                &rule.strict_transport_security,
+                &mut policy.strict_transport_security,
+            );
+            merge(&rule.permissions_policy, &mut policy.permissions_policy);
+            merge(&rule.x_frame_options, &mut policy.x_frame_options);
+        }
+        policy
+    }
+}
+
+impl Policy {
+    /// Renders the header lines for a response to a request with the given Origin header.
+    pub fn headers(&self, origin: Option<&str>) -> String {
+        let mut headers = String::new();
+        let mut push = |name: &str, value: Option<&str>| {
+            if let Some(value) = value {
+                headers.push_str(name);
+                headers.push_str(": ");
+                headers.push_str(value);
+                headers.push_str("\r\n");
+            }
+        };
+        push(
+            "Content-Security-Policy",
+            self.content_security_policy.as_deref(),
+        );
+        push(
+            "Cross-Origin-Embedder-Policy",
+            self.cross_origin_embedder_policy.as_deref(),
+        );
+        push(
+            "Cross-Origin-Resource-Policy",
+            self.cross_origin_resource_policy.as_deref(),
+        );
+        push(
+            "Cross-Origin-Opener-Policy",
+            self.cross_origin_opener_policy.as_deref(),
+        );
+        push(
+            "Strict-Transport-Security",
+            self.strict_transport_security.as_deref(),
+        );
+        push("Permissions-Policy", self.permissions_policy.as_deref());
+        push("X-Frame-Options", self.x_frame_options.as_deref());
+        push(
+            "Access-Control-Allow-Origin",
+            origin.and_then(|origin| {
+                if self.cors_origins.iter().any(|it| it == "*") {
+                    Some("*")
+                } else if allowed_origin(&self.cors_origins, origin) {
+                    Some(origin)
+                } else {
+                    None
+                }
+            }),
+        );
+        headers
+    }
+}
+
+fn validate_rule(rule: &Rule) -> std::result::Result<(), String> {
+    if !rule.path.starts_with('/') || rule.path.contains("***") {
+        return Err("the path glob should start with / and use *, ** or ?".to_string());
+    }
+    let values = [
+        &rule.content_security_policy,
+        &rule.cross_origin_embedder_policy,
+        &rule.cross_origin_resource_policy,
+        &rule.cross_origin_opener_policy,
+        &rule.strict_transport_security,
+        &rule.permissions_policy,
+        &rule.x_frame_options,
+    ];
+    if values
+        .iter()
+        .filter_map(|it| it.as_ref())
+        .any(|it| it.bytes().any(|it| it.is_ascii_control()))
+    {
+        return Err("header values can't contain control characters".to_string());
+    }
+    let one_of = |value: &Option<String>, name: &str, allowed: &[&str]| match value.as_deref() {
+        Some(value) if !value.is_empty() && !allowed.contains(&value) => {
+            Err(format!("{} should be one of {}", name, allowed.join(", ")))
+        }
+        _ => Ok(()),
+    };
+    one_of(
+        &rule.cross_origin_embedder_policy,
+        "cross-origin-embedder-policy",
+        &["unsafe-none", "require-corp", "credentialless"],
+    )?;
+    one_of(
+        &rule.cross_origin_resource_policy,
+        "cross-origin-resource-policy",
+        &["same-site", "same-origin", "cross-origin"],
+    )?;
+    one_of(
+        &rule.cross_origin_opener_policy,
+        "cross-origin-opener-policy",
+        &[
+            "unsafe-none",
+            "same-origin-allow-popups",
+            "same-origin",
+            "noopener-allow-popups",
+        ],
+    )?;
+    one_of(
+        &rule.x_frame_options,
+        "x-frame-options",
+        &["DENY", "SAMEORIGIN"],
+    )?;
+    if let Some(csp) = rule.content_security_policy.as_deref() {
+        let valid = csp
+            .split(';')
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+            .all(|directive| {
+                directive.split_whitespace().next().map_or(false, |name| {
+                    name.bytes()
+                        .all(|it| it.is_ascii_alphanumeric() || it == b'-')
+                })
+            });
+        if !valid {
+            return Err("invalid content-security-policy directive".to_string());
+        }
+    }
+    if let Some(hsts) = rule.strict_transport_security.as_deref() {
+        let max_age = hsts.split(';').find_map(|it| {
+            let (name, value) = it.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("max-age") {
+                Some(value.trim())
+            } else {
+                None
+            }
+        });
+        if !hsts.is_empty()
+            && !max_age.map_or(false, |it| {
+                !it.is_empty() && it.bytes().all(|it| it.is_ascii_digit())
+            })
+        {
+            return Err("strict-transport-security needs a max-age".to_string());
+        }
+    }
+    if let Some(origins) = rule.cors_origins.as_ref() {
+        validate_origins(origins).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn validate_origins(origins: &[String]) -> Result<()> {
+    for origin in origins {
+        let valid = origin == "*"
+            || origin
+                .strip_prefix("https://")
+                .or_else(|| origin.strip_prefix("http://"))
+                .map_or(false, |host| {
+                    !host.is_empty()
+                        && host.bytes().all(|it| {
+                            it.is_ascii_alphanumeric() || it == b'.' || it == b'-' || it == b':'
+                        })
+                });
+        if !valid {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid CORS origin {}", origin),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Matches a url path against a glob where `*` matches anything but `/`, `**` matches anything,
+/// `**/` also matches no directory at all, and `?` matches a single character other than `/`.
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, path) = (pattern.as_bytes(), path.as_bytes());
+    fn matches(pattern: &[u8], path: &[u8]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            [b'*', b'*', b'/', rest @ ..] => {
+                matches(rest, path)
+                    || (0..path.len()).any(|i| path[i] == b'/' && matches(rest, &path[i + 1..]))
+            }
+            [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| matches(rest, &path[i..])),
+            [b'*', rest @ ..] => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != b'/')
+                .any(|i| matches(rest, &path[i..])),
+            [b'?', rest @ ..] => !path.is_empty() && path[0] != b'/' && matches(rest, &path[1..]),
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    matches(pattern, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::policy::{glob_matches, Rules};
+    use std::fs::write;
+    use std::io::Result;
+    use tempfile::tempdir;
+
+    fn load(json: &str) -> Result<Rules> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("headers.json");
+        write(&path, json).unwrap();
+        Rules::load(path.to_str())
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("/**", "/"));
+        assert!(glob_matches("/**", "/a/b/c.js"));
+        assert!(glob_matches("/*.html", "/index.html"));
+        assert!(!glob_matches("/*.html", "/a/index.html"));
+        assert!(glob_matches("/**/*.html", "/index.html"));
+        assert!(glob_matches("/**/*.html", "/a/b/index.html"));
+        assert!(glob_matches("/fonts/**", "/fonts/a.woff2"));
+        assert!(!glob_matches("/fonts/**", "/fontsx/a.woff2"));
+        assert!(glob_matches("/a?c.js", "/abc.js"));
+        assert!(!glob_matches("/a?c.js", "/a/c.js"));
+        assert!(glob_matches("/sw.mjs", "/sw.mjs"));
+        assert!(!glob_matches("/sw.mjs", "/sw.mjs.map"));
+    }
+
+    #[test]
+    fn merge_order() {
+        let rules = load(
+            r#"[
+                {"path": "/**", "x-frame-options": "DENY", "permissions-policy": "camera=()"},
+                {"path": "/**/*.html", "x-frame-options": "SAMEORIGIN", "cors-origins": ["*"]},
+                {"path": "/embed/**", "x-frame-options": "", "cross-origin-embedder-policy": ""}
+            ]"#,
+        )
+        .unwrap();
+        let headers = rules.policy("/app.js").headers(Some("https://example.com"));
+        assert!(headers.contains("\r\nX-Frame-Options: DENY\r\n"));
+        assert!(headers.contains("\r\nPermissions-Policy: camera=()\r\n"));
+        assert!(headers.contains("Cross-Origin-Embedder-Policy: require-corp\r\n"));
+        assert!(!headers.contains("Access-Control-Allow-Origin"));
+        // later rules override the earlier ones and inherit what they don't set
+        let headers = rules
+            .policy("/index.html")
+            .headers(Some("https://example.com"));
+        assert!(headers.contains("\r\nX-Frame-Options: SAMEORIGIN\r\n"));
+        assert!(headers.contains("\r\nPermissions-Policy: camera=()\r\n"));
+        assert!(headers.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+        // empty values remove the header
+        let headers = rules.policy("/embed/index.html").headers(None);
+        assert!(!headers.contains("X-Frame-Options"));
+        assert!(!headers.contains("Cross-Origin-Embedder-Policy"));
+        assert!(headers.contains("\r\nPermissions-Policy: camera=()\r\n"));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(load(r#"[{"path": "/**", "x-frame-options": "DENY"}]"#).is_ok());
+        assert!(load(r#"[{"path": "/**", "x-powered-by": "packurl"}]"#).is_err());
+        assert!(load(r#"[{"path": "/**", "X-Frame-Options": "DENY"}]"#).is_err());
+        assert!(load(r#"[{"path": "*.html", "x-frame-options": "DENY"}]"#).is_err());
+        assert!(load(r#"[{"path": "/***", "x-frame-options": "DENY"}]"#).is_err());
+        assert!(load(r#"[{"path": "/**", "x-frame-options": "ALLOW-FROM x"}]"#).is_err());
+        assert!(
+            load(r#"[{"path": "/**", "permissions-policy": "a=()\r\nSet-Cookie: a"}]"#).is_err()
+        );
+        assert!(load(r#"[{"path": "/**", "cross-origin-opener-policy": "none"}]"#).is_err());
+        assert!(load(
+            r#"[{"path": "/**", "content-security-policy": "default-src 'self'; 'none'"}]"#
+        )
+        .is_err());
+        assert!(
+            load(r#"[{"path": "/**", "strict-transport-security": "includeSubDomains"}]"#).is_err()
+        );
+        assert!(load(r#"[{"path": "/**", "strict-transport-security": ""}]"#).is_ok());
+        assert!(load(r#"[{"path": "/**", "cors-origins": ["example.com"]}]"#).is_err());
+        assert!(load(r#"{"path": "/**"}"#).is_err());
+    }
+}
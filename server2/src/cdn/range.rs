@@ -0,0 +1,151 @@
+use std::ops::Range;
+
+/// More ranges than this in a single request are treated as abuse and the full body is sent.
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRanges {
+    /// The header is missing, malformed or uses an unknown unit: the full body should be sent.
+    Ignored,
+    /// None of the ranges overlap the body: 416 Range Not Satisfiable.
+    Unsatisfiable,
+    /// The ranges to send with 206 Partial Content, in the order they were requested.
+    Satisfiable(Vec<Range<u64>>),
+}
+
+/// Parses a Range header value (`bytes=0-499, 1000-, -200`) for a body of the given length.
+pub fn parse_ranges(range: Option<&str>, len: u64) -> ByteRanges {
+    let range = match range {
+        Some(it) => it.trim(),
+        None => return ByteRanges::Ignored,
+    };
+    let specs = match range.split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return ByteRanges::Ignored,
+    };
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs
+        .split(',')
+        .map(|it| it.trim())
+        .filter(|it| !it.is_empty())
+    {
+        count += 1;
+        if count > MAX_RANGES {
+            return ByteRanges::Ignored;
+        }
+        let (first, last) = match spec.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => return ByteRanges::Ignored,
+        };
+        let range = if first.is_empty() {
+            // suffix range: the last n bytes
+            let suffix = match parse_position(last) {
+                Some(it) => it,
+                None => return ByteRanges::Ignored,
+            };
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            len.saturating_sub(suffix)..len
+        } else {
+            let first = match parse_position(first) {
+                Some(it) => it,
+                None => return ByteRanges::Ignored,
+            };
+            let last = if last.is_empty() {
+                None
+            } else {
+                match parse_position(last) {
+                    Some(it) if it >= first => Some(it),
+                    _ => return ByteRanges::Ignored,
+                }
+            };
+            if first >= len {
+                continue;
+            }
+            first..last.map_or(len, |it| it.saturating_add(1).min(len))
+        };
+        ranges.push(range);
+    }
+    if count == 0 {
+        ByteRanges::Ignored
+    } else if ranges.is_empty() {
+        ByteRanges::Unsatisfiable
+    } else {
+        ByteRanges::Satisfiable(ranges)
+    }
+}
+
+fn parse_position(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|it| it.is_ascii_digit()) {
+        return None;
+    }
+    // positions larger than any body we could hold are clamped rather than rejected
+    Some(value.parse().unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::range::{parse_ranges, ByteRanges};
+
+    #[test]
+    fn ignored() {
+        assert_eq!(parse_ranges(None, 100), ByteRanges::Ignored);
+        assert_eq!(parse_ranges(Some("items=0-1"), 100), ByteRanges::Ignored);
+        assert_eq!(parse_ranges(Some("bytes="), 100), ByteRanges::Ignored);
+        assert_eq!(parse_ranges(Some("bytes=5-2"), 100), ByteRanges::Ignored);
+        assert_eq!(parse_ranges(Some("bytes=a-2"), 100), ByteRanges::Ignored);
+        assert_eq!(parse_ranges(Some("bytes=0-1,x"), 100), ByteRanges::Ignored);
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn satisfiable() {
+        assert_eq!(
+            parse_ranges(Some("bytes=0-499"), 1000),
+            ByteRanges::Satisfiable(vec![0..500])
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=900-"), 1000),
+            ByteRanges::Satisfiable(vec![900..1000])
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=-200"), 1000),
+            ByteRanges::Satisfiable(vec![800..1000])
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=-2000"), 1000),
+            ByteRanges::Satisfiable(vec![0..1000])
+        );
+        assert_eq!(
+            parse_ranges(Some("Bytes=0-0, 990-99999"), 1000),
+            ByteRanges::Satisfiable(vec![0..1, 990..1000])
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=2000-, 10-19"), 1000),
+            ByteRanges::Satisfiable(vec![10..20])
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=0-18446744073709551615"), 1000),
+            ByteRanges::Satisfiable(vec![0..1000])
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=10-99999999999999999999999"), 1000),
+            ByteRanges::Satisfiable(vec![10..1000])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert_eq!(
+            parse_ranges(Some("bytes=1000-"), 1000),
+            ByteRanges::Unsatisfiable
+        );
+        assert_eq!(
+            parse_ranges(Some("bytes=-0"), 1000),
+            ByteRanges::Unsatisfiable
+        );
+        assert_eq!(parse_ranges(Some("bytes=0-"), 0), ByteRanges::Unsatisfiable);
+    }
+}
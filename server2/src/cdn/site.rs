@@ -0,0 +1,322 @@
+use crate::cdn::cache::Files;
+use crate::cdn::policy::Rules;
+use crate::domains::{APEX, CDN, LOCALHOST, LOCALHOST_IPV4, LOCALHOST_IPV6, WWW};
+use crate::log::LogLevel;
+use arc_swap::ArcSwap;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env::var;
+use std::fs::read;
+use std::io::{Error, ErrorKind, Result};
+use tokio::sync::Mutex;
+
+lazy_static! {
+    pub static ref ROOT: String = var("XDG_WWW_ROOT").unwrap_or("/var/www".to_string());
+    pub static ref PREFIX: String = var("XDG_WWW_PREFIX").unwrap_or("/".to_string());
+    static ref HEADERS_FILE: Option<String> = var("XDG_WWW_HEADERS").ok();
+    static ref VHOSTS_FILE: Option<String> = var("XDG_WWW_VHOSTS").ok();
+    // The defaults of the CDN site, virtual hosts have their own settings.
+    static ref SPA_PREFIX: Option<String> = var("XDG_WWW_SPA_PREFIX").ok().and_then(dir_prefix);
+    static ref LISTINGS: Vec<String> = var("XDG_WWW_LISTINGS")
+        .map(|it| it.split(',').filter_map(|it| dir_prefix(it.trim())).collect())
+        .unwrap_or_default();
+    static ref HASHED_PATHS: bool = var("XDG_WWW_HASHED_PATHS")
+        .map(|it| it != "0" && !it.eq_ignore_ascii_case("false"))
+        .unwrap_or(false);
+    /// Like the asset manifest, the integrity index is only generated when enabled,
+    /// by setting its name (e.g. `integrity.json`).
+    static ref INTEGRITY_INDEX: Option<String> =
+        var("XDG_WWW_INTEGRITY_INDEX").ok().filter(|it| !it.is_empty());
+    static ref SITES: std::result::Result<Vec<Site>, String> =
+        load_sites().map_err(|err| err.to_string());
+}
+
+/// A host served from the CDN cache, with its own web root, prefix, header rules and entries.
+pub struct Site {
+    pub host: String,
+    pub root: String,
+    pub prefix: String,
+    pub rules: Rules,
+    /// The prefix of the single page app, whose index is served for unknown routes.
+    pub spa_prefix: Option<String>,
+    /// Directories (and everything under them) that get a listing when they have no index.html.
+    pub listings: Vec<String>,
+    /// Whether files are also served under hashed paths, with an asset manifest.
+    pub hashed_paths: bool,
+    /// The name of the generated integrity index, if enabled.
+    pub integrity_index: Option<String>,
+    /// Whether the certificate of the host is part of the ACME order.
+    pub acme: bool,
+    /// The current version of the cache, replaced as a whole by updates.
+    pub files: ArcSwap<Files>,
+    pub lock: Mutex<()>,
+}
+
+/// An additional site, configured in the json array of the XDG_WWW_VHOSTS file:
+/// `[{ "host": "templates.packurl.net", "root": "/home/admin/templates", "headers": "..." }]`
+/// The optional `spa-prefix`, `listings`, `hashed-paths` and `integrity-index` fields have the
+/// same meaning as the XDG_WWW_* variables of the CDN site, and are disabled by default.
+/// Hosts with `"acme": true` are added to the ACME order, others get a self-signed certificate
+/// unless XDG_TLS_CERTIFICATES specifies another source.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct SiteConfig {
+    host: String,
+    root: String,
+    prefix: Option<String>,
+    headers: Option<String>,
+    acme: Option<bool>,
+    spa_prefix: Option<String>,
+    listings: Option<Vec<String>>,
+    hashed_paths: Option<bool>,
+    integrity_index: Option<String>,
+}
+
+impl Site {
+    pub fn new(host: String, root: String, prefix: String, rules: Rules, acme: bool) -> Self {
+        Site {
+            host,
+            root,
+            prefix,
+            rules,
+            spa_prefix: None,
+            listings: Vec::new(),
+            hashed_paths: false,
+            integrity_index: None,
+            acme,
+            files: ArcSwap::from_pointee(HashMap::new()),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Checks the sites configuration, so that errors are reported at startup.
+    pub fn validate() -> Result<()> {
+        match SITES.as_ref() {
+            Ok(sites) => {
+                if let Some(path) = VHOSTS_FILE.as_ref() {
+                    LogLevel::Info.log(|| {
+                        println!(
+                            "Loaded {} virtual hosts from {}",
+                            sites.len() - 1,
+                            path.yellow()
+                        )
+                    });
+                }
+                Ok(())
+            }
+            Err(err) => Err(Error::new(ErrorKind::InvalidInput, err.clone())),
+        }
+    }
+
+    /// Returns all the sites, starting with the one for the CDN host.
+    pub fn all() -> &'static [Site] {
+        SITES.as_deref().unwrap_or_default()
+    }
+
+    pub fn for_host(host: &str) -> Option<&'static Site> {
+        Self::all().iter().find(|it| it.host == host)
+    }
+
+    /// A site serving the given root for test.packurl.net, with the default settings.
+    #[cfg(test)]
+    pub fn for_test(root: &std::path::Path) -> &'static Site {
+        Box::leak(Box::new(Site::new(
+            "test.packurl.net".to_string(),
+            root.to_str().unwrap().to_string(),
+            "/".to_string(),
+            Rules::load(None).unwrap(),
+            false,
+        )))
+    }
+}
+
+fn load_sites() -> Result<Vec<Site>> {
+    let mut sites = vec![Site {
+        spa_prefix: SPA_PREFIX.clone(),
+        listings: LISTINGS.clone(),
+        hashed_paths: *HASHED_PATHS,
+        integrity_index: INTEGRITY_INDEX.clone(),
+        ..Site::new(
+            CDN.to_string(),
+            ROOT.to_string(),
+            PREFIX.to_string(),
+            Rules::load(HEADERS_FILE.as_deref())?,
+            false,
+        )
+    }];
+    if let Some(path) = VHOSTS_FILE.as_ref() {
+        add_vhosts(&mut sites, path, &read(path)?)?;
+    }
+    Ok(sites)
+}
+
+// Adds the virtual hosts of the json file to the sites, checking their configuration.
+fn add_vhosts(sites: &mut Vec<Site>, path: &str, json: &[u8]) -> Result<()> {
+    let configs: Vec<SiteConfig> = serde_json::from_slice(json).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid virtual hosts {}: {}", path, err),
+        )
+    })?;
+    for config in configs {
+        let host = config.host.to_lowercase();
+        let prefix = config.prefix.unwrap_or("/".to_string());
+        let invalid = |reason: &str| {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid virtual host {}: {}", host, reason),
+            ))
+        };
+        if host.is_empty()
+            || !host
+                .bytes()
+                .all(|it| it.is_ascii_alphanumeric() || it == b'.' || it == b'-')
+        {
+            return invalid("invalid host name");
+        }
+        if [APEX, WWW, LOCALHOST, LOCALHOST_IPV4, LOCALHOST_IPV6].contains(&host.as_str())
+            || sites.iter().any(|it| it.host == host)
+        {
+            return invalid("the host is already served");
+        }
+        if config.root.is_empty() {
+            return invalid("missing root");
+        }
+        if !prefix.starts_with('/') || !prefix.ends_with('/') {
+            return invalid("the prefix should start and end with /");
+        }
+        let spa_prefix = match config.spa_prefix.as_deref().map(dir_prefix) {
+            Some(None) => return invalid("the spa prefix should start with /"),
+            Some(it) => it,
+            None => None,
+        };
+        let listings = match config
+            .listings
+            .unwrap_or_default()
+            .iter()
+            .map(dir_prefix)
+            .collect::<Option<Vec<String>>>()
+        {
+            Some(it) => it,
+            None => return invalid("the listings should start with /"),
+        };
+        let rules = Rules::load(config.headers.as_deref())?;
+        let acme = config.acme.unwrap_or(false);
+        sites.push(Site {
+            spa_prefix,
+            listings,
+            hashed_paths: config.hashed_paths.unwrap_or(false),
+            integrity_index: config.integrity_index.filter(|it| !it.is_empty()),
+            ..Site::new(host, config.root, prefix, rules, acme)
+        });
+    }
+    Ok(())
+}
+
+// Returns the path with a trailing slash, or None if it doesn't start with a slash.
+fn dir_prefix(path: impl AsRef<str>) -> Option<String> {
+    let path = path.as_ref();
+    if !path.starts_with('/') {
+        None
+    } else if path.ends_with('/') {
+        Some(path.to_string())
+    } else {
+        Some(format!("{}/", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::site::{add_vhosts, Site};
+    use crate::domains::CDN;
+    use std::io::Result;
+    use std::path::Path;
+
+    fn load(json: &str) -> Result<Vec<Site>> {
+        let mut sites = vec![Site::new(
+            CDN.to_string(),
+            "/var/www".to_string(),
+            "/".to_string(),
+            Default::default(),
+            false,
+        )];
+        add_vhosts(&mut sites, "vhosts.json", json.as_bytes())?;
+        Ok(sites)
+    }
+
+    #[test]
+    fn vhosts() {
+        let sites = load(
+            r#"[
+                {"host": "Templates.packurl.net", "root": "/srv/templates"},
+                {
+                    "host": "app.packurl.net", "root": "/srv/app", "prefix": "/app/",
+                    "acme": true, "spa-prefix": "/app", "listings": ["/files"],
+                    "hashed-paths": true, "integrity-index": "integrity.json"
+                }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(sites.len(), 3);
+        let templates = &sites[1];
+        assert_eq!(templates.host, "templates.packurl.net");
+        assert_eq!(templates.prefix, "/");
+        assert!(!templates.acme && !templates.hashed_paths);
+        assert!(templates.spa_prefix.is_none() && templates.integrity_index.is_none());
+        let app = &sites[2];
+        assert_eq!(app.prefix, "/app/");
+        assert!(app.acme && app.hashed_paths);
+        assert_eq!(app.spa_prefix.as_deref(), Some("/app/"));
+        assert_eq!(app.listings, vec!["/files/"]);
+        assert_eq!(app.integrity_index.as_deref(), Some("integrity.json"));
+        assert!(load("[]").is_ok());
+    }
+
+    #[test]
+    fn invalid_vhosts() {
+        assert!(load(r#"[{"host": "a.packurl.net"}]"#).is_err());
+        assert!(load(r#"[{"host": "a.packurl.net", "root": "/a", "cache": true}]"#).is_err());
+        assert!(load(r#"[{"host": "a.packurl.net", "root": "/a", "spa_prefix": "/"}]"#).is_err());
+        assert!(load(r#"{"host": "a.packurl.net", "root": "/a"}"#).is_err());
+        assert!(load(r#"[{"host": "", "root": "/a"}]"#).is_err());
+        assert!(load(r#"[{"host": "a.packurl.net/x", "root": "/a"}]"#).is_err());
+        assert!(load(r#"[{"host": "a.packurl.net", "root": ""}]"#).is_err());
+        // reserved and duplicate hosts
+        for host in [
+            CDN,
+            "packurl.net",
+            "www.packurl.net",
+            "localhost",
+            "127.0.0.1",
+            "::1",
+        ] {
+            let json = format!(r#"[{{"host": "{}", "root": "/a"}}]"#, host);
+            assert!(load(&json).is_err(), "{}", host);
+        }
+        assert!(load(
+            r#"[{"host": "a.packurl.net", "root": "/a"}, {"host": "A.packurl.net", "root": "/b"}]"#
+        )
+        .is_err());
+        // prefixes
+        assert!(load(r#"[{"host": "a.packurl.net", "root": "/a", "prefix": "app/"}]"#).is_err());
+        assert!(load(r#"[{"host": "a.packurl.net", "root": "/a", "prefix": "/app"}]"#).is_err());
+        assert!(load(r#"[{"host": "a.packurl.net", "root": "/a", "spa-prefix": "app"}]"#).is_err());
+        assert!(
+            load(r#"[{"host": "a.packurl.net", "root": "/a", "listings": ["/", "x"]}]"#).is_err()
+        );
+        // header rules are loaded (and checked) for each host
+        assert!(
+            load(r#"[{"host": "a.packurl.net", "root": "/a", "headers": "/missing.json"}]"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_site() {
+        let site = Site::for_test(Path::new("/tmp/www"));
+        assert_eq!(site.root, "/tmp/www");
+        assert!(site.files.load().is_empty());
+    }
+}
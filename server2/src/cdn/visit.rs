@@ -0,0 +1,122 @@
+use lazy_static::lazy_static;
+use std::env::var;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use tokio::fs::{canonicalize, metadata, symlink_metadata};
+
+lazy_static! {
+    /// Whether symbolic links are followed (only when their target is inside the web root).
+    static ref FOLLOW_SYMLINKS: bool = var("XDG_WWW_FOLLOW_SYMLINKS")
+        .map(|it| it != "0" && !it.eq_ignore_ascii_case("false"))
+        .unwrap_or(true);
+    /// Names of the dot-prefixed files and directories that are served anyway.
+    static ref DOTFILES: Vec<String> = var("XDG_WWW_DOTFILES")
+        .unwrap_or(".well-known".to_string())
+        .split(',')
+        .map(|it| it.trim().trim_end_matches('/').to_string())
+        .filter(|it| it.starts_with('.') && it.len() > 1)
+        .collect();
+}
+
+/// What the walker should do with a path of the web root.
+pub enum Visit {
+    /// A directory to walk into, with its canonical path for loop detection.
+    Dir(PathBuf),
+    File,
+    /// A path that is left out of the cache, with the reason.
+    Skip(&'static str),
+    /// Anything else (sockets, fifos, ...), ignored silently.
+    Other,
+}
+
+/// Returns true for dot-prefixed names that aren't allow-listed.
+pub fn is_hidden(name: &str) -> bool {
+    name.starts_with('.') && !DOTFILES.iter().any(|it| it == name)
+}
+
+/// Decides how to walk the path, given the canonical web root and the canonical paths of the
+/// directories being walked.
+/// Symbolic links are resolved only when they are enabled and point inside the root,
+/// and directories that are already being walked are skipped as loops.
+pub async fn visit(root: &Path, path: &Path, ancestors: &[PathBuf]) -> Result<Visit> {
+    if symlink_metadata(path).await?.is_symlink() {
+        if !*FOLLOW_SYMLINKS {
+            return Ok(Visit::Skip("symlink"));
+        }
+        match canonicalize(path).await {
+            Ok(target) if target.starts_with(root) => {}
+            Ok(_) => return Ok(Visit::Skip("symlink outside the root")),
+            Err(_) => return Ok(Visit::Skip("broken symlink")),
+        }
+    }
+    let stat = metadata(path).await?;
+    if stat.is_dir() {
+        let canonical = canonicalize(path).await?;
+        if ancestors.contains(&canonical) {
+            return Ok(Visit::Skip("symlink loop"));
+        }
+        Ok(Visit::Dir(canonical))
+    } else if stat.is_file() {
+        Ok(Visit::File)
+    } else {
+        Ok(Visit::Other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cdn::visit::{is_hidden, visit, Visit};
+    use std::fs::{create_dir, write};
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn hidden_names() {
+        assert!(is_hidden(".git"));
+        assert!(is_hidden(".DS_Store"));
+        assert!(!is_hidden(".well-known"));
+        assert!(!is_hidden("index.html"));
+    }
+
+    #[tokio::test]
+    async fn symlinks() {
+        let temp = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let dir = root.join("dir");
+        create_dir(&dir).unwrap();
+        write(dir.join("file.txt"), "text").unwrap();
+        write(outside.path().join("secret.txt"), "secret").unwrap();
+        symlink(&dir, root.join("inside")).unwrap();
+        symlink(dir.join("file.txt"), root.join("file.txt")).unwrap();
+        symlink(outside.path(), root.join("outside")).unwrap();
+        symlink(outside.path().join("secret.txt"), root.join("secret.txt")).unwrap();
+        symlink(&root, dir.join("loop")).unwrap();
+        symlink(root.join("missing"), root.join("broken")).unwrap();
+
+        let ancestors = vec![root.clone()];
+        let inside = visit(&root, &root.join("inside"), &ancestors).await;
+        assert!(matches!(inside, Ok(Visit::Dir(it)) if it == dir));
+        assert!(matches!(
+            visit(&root, &root.join("file.txt"), &ancestors).await,
+            Ok(Visit::File)
+        ));
+        assert!(matches!(
+            visit(&root, &root.join("outside"), &ancestors).await,
+            Ok(Visit::Skip("symlink outside the root"))
+        ));
+        assert!(matches!(
+            visit(&root, &root.join("secret.txt"), &ancestors).await,
+            Ok(Visit::Skip("symlink outside the root"))
+        ));
+        assert!(matches!(
+            visit(&root, &root.join("broken"), &ancestors).await,
+            Ok(Visit::Skip("broken symlink"))
+        ));
+        let ancestors = vec![root.clone(), dir.clone()];
+        assert!(matches!(
+            visit(&root, &dir.join("loop"), &ancestors).await,
+            Ok(Visit::Skip("symlink loop"))
+        ));
+    }
+}
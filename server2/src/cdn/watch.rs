@@ -0,0 +1,96 @@
+use crate::cdn::cache::Cache;
+use crate::cdn::site::Site;
+use crate::log::LogLevel;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use notify::event::EventKind;
+use notify::{recommended_watcher, Event, RecursiveMode, Watcher};
+use std::env::var;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::{timeout, Duration, Instant};
+
+// Wait for the file system to be quiet for that long before updating the cache.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+// But don't wait longer than that if changes keep coming in.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref WATCH: bool = var("XDG_WWW_WATCH")
+        .map(|it| it != "0" && !it.eq_ignore_ascii_case("false"))
+        .unwrap_or(true);
+}
+
+pub fn watch(site: &'static Site) {
+    if !*WATCH {
+        return;
+    }
+    let (sender, mut receiver) = unbounded_channel::<PathBuf>();
+    let watcher = recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            match event.kind {
+                EventKind::Access(_) | EventKind::Other => {}
+                _ => event.paths.into_iter().for_each(|it| {
+                    let _ = sender.send(it);
+                }),
+            }
+        }
+    })
+    .and_then(|mut watcher| {
+        let root = Path::new(site.root.as_str());
+        // The web root can be a symlink that gets swapped by a deployment.
+        if let Some(parent) = root.parent() {
+            watcher.watch(parent, RecursiveMode::NonRecursive)?;
+        }
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map(|_| watcher)
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            LogLevel::Warning.log(|| {
+                println!("{}", "Failed to watch web root for changes".red());
+                println!("{:?}", err);
+            });
+            return;
+        }
+    };
+    LogLevel::Info.log(|| println!("Watching {} for changes", site.root.yellow()));
+    tokio::spawn(async move {
+        let root = Path::new(site.root.as_str());
+        while let Some(path) = receiver.recv().await {
+            let mut paths = vec![path];
+            let deadline = Instant::now() + MAX_DELAY;
+            while Instant::now() < deadline {
+                match timeout(DEBOUNCE, receiver.recv()).await {
+                    Ok(Some(path)) => paths.push(path),
+                    _ => break,
+                }
+            }
+            if paths.iter().any(|it| it == root) {
+                LogLevel::Info.log(|| println!("{}", "Web root was replaced".purple()));
+                let _ = watcher.unwatch(root);
+                if let Err(err) = watcher.watch(root, RecursiveMode::Recursive) {
+                    LogLevel::Warning.log(|| {
+                        println!("{}", "Failed to watch web root for changes".red());
+                        println!("{:?}", err);
+                    });
+                }
+            }
+            paths.retain(|it| it.starts_with(root));
+            LogLevel::Debug.log(|| {
+                println!(
+                    "{}",
+                    format!("Detected {} file system changes", paths.len()).purple()
+                )
+            });
+            if let Err(err) = Cache::update_paths(site, paths).await {
+                LogLevel::Warning.log(|| {
+                    println!("{}", "Failed to update file cache".red());
+                    println!("{:?}", err);
+                });
+            }
+        }
+    });
+}
@@ -0,0 +1,198 @@
+use crate::deploy::release::{deploy, BRANCH, SECRET};
+use crate::http::{Headers, Request};
+use crate::log::LogLevel;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use ring::hmac;
+use serde_json::Value;
+use std::sync::RwLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+lazy_static! {
+    static ref LAST_REPORT: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Returns the report of the last deployment (or of the one in progress), if any.
+pub fn last_report() -> Option<String> {
+    LAST_REPORT.read().ok()?.clone()
+}
+
+fn set_report(report: String) {
+    if let Ok(mut lock) = LAST_REPORT.write() {
+        *lock = Some(report);
+    }
+}
+
+/// Handles a GitHub webhook request. The request head, and maybe the start of the body,
+/// have already been read.
+pub async fn handle_deploy_request(
+    stream: &mut TlsStream<TcpStream>,
+    request: &Request,
+    body_start: Vec<u8>,
+) {
+    let (status, body) = match read_body(stream, request, body_start).await {
+        Ok(body) => process_webhook(&request.headers, &body).await,
+        Err(status) => (status, String::new()),
+    };
+    let text = format!(
+        "\
+HTTP/1.1 {}\r\n\
+Cache-Control: no-store\r\n\
+Connection: close\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: {}\r\n\
+\r\n",
+        status,
+        body.len()
+    );
+    let _ = stream.write_all(text.as_bytes()).await;
+    let _ = stream.write_all(body.as_bytes()).await;
+}
+
+async fn process_webhook(headers: &Headers, body: &[u8]) -> (&'static str, String) {
+    let secret = match SECRET.as_ref() {
+        Some(secret) => secret,
+        None => return ("404 Not Found", String::new()),
+    };
+    let header = |name: &str| headers.get(name);
+    if !verify_signature(secret, header("x-hub-signature-256"), body) {
+        LogLevel::Warning.log(|| println!("{}", "Rejected webhook with invalid signature".red()));
+        return ("401 Unauthorized", "Invalid signature\n".to_string());
+    }
+    match header("x-github-event") {
+        Some("ping") => ("200 OK", "pong\n".to_string()),
+        Some("push") => {
+            let payload: Value = match serde_json::from_slice(body) {
+                Ok(payload) => payload,
+                Err(_) => return ("400 Bad Request", "Invalid payload\n".to_string()),
+            };
+            let branch = format!("refs/heads/{}", BRANCH.as_str());
+            if payload["ref"].as_str() != Some(branch.as_str()) {
+                return (
+                    "202 Accepted",
+                    format!("Ignoring push to {}\n", payload["ref"]),
+                );
+            }
+            let sha = payload["after"].as_str().unwrap_or("unknown").to_string();
+            LogLevel::Info.log(|| println!("Deploying {}", sha.purple()));
+            let response = format!("Deploying {}\n", sha);
+            // GitHub gives up on webhooks after 10s, so the deployment outlives the request,
+            // and deployments are serialized by the lock in deploy.
+            // Its result is logged and kept for the /deploy endpoint of the local handler.
+            set_report(format!("Deploying {}\nIn progress\n", sha));
+            tokio::spawn(async move {
+                match deploy(&sha).await {
+                    Ok(report) => {
+                        LogLevel::Info.log(|| {
+                            println!("{}", "Deployment succeeded".green());
+                            print!("{}", report);
+                        });
+                        set_report(format!("Deployed {}\n{}", sha, report));
+                    }
+                    Err(err) => {
+                        LogLevel::Warning.log(|| {
+                            println!("{}", "Deployment failed".red());
+                            println!("{:?}", err);
+                        });
+                        set_report(format!("Failed to deploy {}\n{:?}\n", sha, err));
+                    }
+                }
+            });
+            ("202 Accepted", response)
+        }
+        _ => ("202 Accepted", "Ignoring event\n".to_string()),
+    }
+}
+
+async fn read_body(
+    stream: &mut TlsStream<TcpStream>,
+    request: &Request,
+    body_start: Vec<u8>,
+) -> Result<Vec<u8>, &'static str> {
+    let length = request
+        .content_length()
+        .map_err(|err| err.status())?
+        .ok_or("411 Length Required")?;
+    if length > MAX_BODY_SIZE {
+        return Err("413 Payload Too Large");
+    }
+    let mut body = body_start;
+    if body.len() < length {
+        let start = body.len();
+        body.resize(length, 0);
+        stream
+            .read_exact(&mut body[start..])
+            .await
+            .map_err(|_| "400 Bad Request")?;
+    }
+    body.truncate(length);
+    Ok(body)
+}
+
+// Checks the X-Hub-Signature-256 header, the hmac of the body with the shared secret.
+fn verify_signature(secret: &str, header: Option<&str>, body: &[u8]) -> bool {
+    header
+        .and_then(|it| it.strip_prefix("sha256="))
+        .and_then(decode_hex)
+        .map_or(false, |signature| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            hmac::verify(&key, body, &signature).is_ok()
+        })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would accept a leading sign
+    if hex.len() % 2 != 0 || !hex.bytes().all(|it| it.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deploy::handler::{decode_hex, verify_signature};
+
+    // The example from the GitHub documentation on validating webhook deliveries.
+    const SECRET: &str = "It's a Secret to Everybody";
+    const PAYLOAD: &[u8] = b"Hello, World!";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn valid_signature() {
+        assert!(verify_signature(SECRET, Some(SIGNATURE), PAYLOAD));
+    }
+
+    #[test]
+    fn invalid_signature() {
+        assert!(!verify_signature(SECRET, Some(SIGNATURE), b"Hello, World?"));
+        assert!(!verify_signature("secret", Some(SIGNATURE), PAYLOAD));
+        assert!(!verify_signature(SECRET, Some(&SIGNATURE[7..]), PAYLOAD));
+        assert!(!verify_signature(
+            SECRET,
+            Some(&SIGNATURE[..SIGNATURE.len() - 2]),
+            PAYLOAD
+        ));
+        assert!(!verify_signature(SECRET, Some("sha256="), PAYLOAD));
+        assert!(!verify_signature(SECRET, None, PAYLOAD));
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("00FF"), Some(vec![0x00, 0xff]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        assert_eq!(decode_hex("-1"), None);
+        assert_eq!(decode_hex("é0"), None);
+    }
+}
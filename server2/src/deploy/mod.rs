@@ -0,0 +1,4 @@
+pub use handler::{handle_deploy_request, last_report};
+
+mod handler;
+mod release;
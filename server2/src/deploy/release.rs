@@ -0,0 +1,189 @@
+use crate::cdn::{Cache, ROOT};
+use crate::log::LogLevel;
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use lazy_static::lazy_static;
+use reqwest::Client;
+use std::env::var;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tar::Archive;
+use tokio::fs::{
+    create_dir_all, read_dir, remove_dir_all, remove_file, rename, symlink, symlink_metadata,
+};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+
+const KEPT_RELEASES: usize = 3;
+
+lazy_static! {
+    pub static ref SECRET: Option<String> = var("GITHUB_SECRET").ok().filter(|it| !it.is_empty());
+    pub static ref BRANCH: String = var("XDG_DEPLOY_BRANCH").unwrap_or("main".to_string());
+    static ref COMMAND: Option<String> = var("XDG_DEPLOY_COMMAND").ok();
+    static ref ARTIFACT: Option<String> = var("XDG_DEPLOY_ARTIFACT").ok();
+    static ref TOKEN: Option<String> = var("XDG_DEPLOY_TOKEN").ok();
+    static ref RELEASES: String = var("XDG_DEPLOY_RELEASES")
+        .unwrap_or_else(|_| format!("{}.releases", ROOT.trim_end_matches('/')));
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Builds the release for the given commit in a new directory next to the previous ones,
+/// points the web root symlink to it, and updates the file cache.
+/// Returns a text report of what was done.
+pub async fn deploy(sha: &str) -> Result<String> {
+    let _lock = LOCK.lock().await;
+    let root = Path::new(ROOT.as_str());
+    match symlink_metadata(root).await {
+        Ok(meta) if !meta.file_type().is_symlink() => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "The web root must be a symlink to be deployed to",
+            ))
+        }
+        _ => {}
+    }
+    let releases = Path::new(RELEASES.as_str());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0);
+    let short_sha: String = sha
+        .chars()
+        .filter(|it| it.is_ascii_alphanumeric())
+        .take(12)
+        .collect();
+    let staging = releases.join(format!("{}-{}", timestamp, short_sha));
+    create_dir_all(&staging).await?;
+    let mut report = format!("Staging {}\n", staging.display());
+    let result = match (COMMAND.as_ref(), ARTIFACT.as_ref()) {
+        (Some(command), _) => run_command(command, &staging, sha).await,
+        (None, Some(url)) => unpack_artifact(&url.replace("{sha}", sha), &staging).await,
+        (None, None) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "Neither a deploy command nor an artifact url is configured",
+        )),
+    };
+    match result {
+        Ok(output) => report.push_str(&output),
+        Err(err) => {
+            let _ = remove_dir_all(&staging).await;
+            return Err(err);
+        }
+    }
+    let release = release_root(&staging).await?;
+    let link = releases.join(".next");
+    let _ = remove_file(&link).await;
+    symlink(&release, &link).await?;
+    rename(&link, root).await?;
+    report.push_str(&format!(
+        "Switched {} to {}\n",
+        root.display(),
+        release.display()
+    ));
+    LogLevel::Info.log(|| {
+        println!(
+            "Switched web root to {}",
+            format!("{}", release.display()).yellow()
+        )
+    });
+    let summary = Cache::update().await?;
+    report.push_str(&format!("Updated file cache\n{}", summary));
+    for old in prune(releases, &staging).await? {
+        report.push_str(&format!("Removed {}\n", old.display()));
+    }
+    Ok(report)
+}
+
+async fn run_command(command: &str, staging: &Path, sha: &str) -> Result<String> {
+    LogLevel::Info.log(|| println!("Running {}", command.yellow()));
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(staging)
+        .env("DEPLOY_DIR", staging)
+        .env("DEPLOY_SHA", sha)
+        .output()
+        .await?;
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if output.status.success() {
+        Ok(text)
+    } else {
+        Err(Error::new(
+            ErrorKind::Other,
+            format!("Deploy command failed with {}\n{}", output.status, text),
+        ))
+    }
+}
+
+async fn unpack_artifact(url: &str, staging: &Path) -> Result<String> {
+    LogLevel::Info.log(|| println!("Downloading {}", url.yellow()));
+    let mut request = Client::new().get(url);
+    if let Some(token) = TOKEN.as_ref() {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    if !response.status().is_success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("Artifact download returned {}", response.status()),
+        ));
+    }
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    let len = bytes.len();
+    let staging = staging.to_path_buf();
+    spawn_blocking(move || Archive::new(GzDecoder::new(bytes.as_ref())).unpack(staging))
+        .await
+        .map_err(|err| Error::new(ErrorKind::Other, err))??;
+    Ok(format!("Unpacked {} ({} bytes)\n", url, len))
+}
+
+// Archives of a repository (like the ones from GitHub) wrap everything in a single directory.
+async fn release_root(staging: &Path) -> Result<PathBuf> {
+    let mut entries = read_dir(staging).await?;
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        found.push(entry);
+        if found.len() > 1 {
+            return Ok(staging.to_path_buf());
+        }
+    }
+    match found.pop() {
+        Some(entry) if entry.file_type().await?.is_dir() => Ok(entry.path()),
+        _ => Ok(staging.to_path_buf()),
+    }
+}
+
+async fn prune(releases: &Path, current: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = read_dir(releases).await?;
+    let mut found = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_str()
+            .map_or(false, |it| !it.starts_with('.'))
+            && entry.file_type().await?.is_dir()
+        {
+            found.push(entry.path());
+        }
+    }
+    found.sort();
+    found.retain(|it| it != current);
+    let count = found.len().saturating_sub(KEPT_RELEASES - 1);
+    let removed: Vec<PathBuf> = found.into_iter().take(count).collect();
+    for it in removed.iter() {
+        remove_dir_all(it).await?;
+    }
+    Ok(removed)
+}
@@ -1,3 +1,6 @@
+use crate::cdn::Site;
+use lazy_static::lazy_static;
+use std::env::var;
 use std::string::ToString;
 
 pub const LOCALHOST: &'static str = "localhost";
@@ -12,6 +15,42 @@ pub const SELF_SIGNED_DOMAINS: DomainList = DomainList {
 pub const ACME_DOMAINS: DomainList = DomainList {
     domains: &[APEX, WWW],
 };
+pub const HTTPS_DOMAINS: DomainList = DomainList {
+    domains: &[APEX, WWW, CDN, LOCALHOST, LOCALHOST_IPV4, LOCALHOST_IPV6],
+};
+
+lazy_static! {
+    /// Host used for clients that don't send SNI or send an unknown server name
+    /// (e.g. IP-based health checks). Set XDG_DEFAULT_HOST to an empty string to disable it.
+    pub static ref DEFAULT_HOST: Option<String> = match var("XDG_DEFAULT_HOST") {
+        Ok(host) if host.is_empty() => None,
+        Ok(host) => Some(host.to_lowercase()),
+        Err(_) => Some(APEX.to_string()),
+    };
+}
+
+/// Returns the domains of the ACME order: the apex domains and the virtual hosts that opted in.
+pub fn acme_domains() -> Vec<String> {
+    ACME_DOMAINS
+        .iter()
+        .map(|it| it.to_string())
+        .chain(
+            Site::all()
+                .iter()
+                .filter(|it| it.acme)
+                .map(|it| it.host.clone()),
+        )
+        .collect()
+}
+
+pub fn host_or_default(sni: Option<&str>) -> Option<&str> {
+    match sni {
+        Some(sni) if HTTPS_DOMAINS.find(sni).is_some() || Site::for_host(sni).is_some() => {
+            Some(sni)
+        }
+        _ => DEFAULT_HOST.as_deref(),
+    }
+}
 
 pub struct DomainList {
     domains: &'static [&'static str],
@@ -27,4 +66,7 @@ impl DomainList {
     pub fn find(&self, needle: &str) -> Option<()> {
         self.domains.iter().find(|&&it| it == needle).map(|_| ())
     }
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> {
+        self.domains.iter().copied()
+    }
 }
@@ -0,0 +1,3 @@
+pub use request::{read_request, Headers, Limits, Method, Request, RequestError};
+
+mod request;
@@ -0,0 +1,418 @@
+use std::str::from_utf8;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const CHUNK_SIZE: usize = 1024;
+
+/// Size limits applied while reading the request head.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum length of the request line (method, target and version).
+    pub request_line: usize,
+    /// Maximum length of the whole head (request line and header fields).
+    pub head: usize,
+    /// Maximum number of header fields.
+    pub headers: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            request_line: 2048,
+            head: 8192,
+            headers: 64,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Options,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Other(String),
+}
+
+impl Method {
+    fn parse(token: &str) -> Self {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "OPTIONS" => Method::Options,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            _ => Method::Other(token.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+/// Header fields, with names stored in lowercase so that lookups are case-insensitive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Returns the value of the first field with that name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(it, _)| it.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the values of all the fields with that name, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(it, _)| it.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    /// The connection was closed before a complete head was received.
+    Closed,
+    BadRequest,
+    UriTooLong,
+    HeadersTooLarge,
+    VersionNotSupported,
+}
+
+impl RequestError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            RequestError::Closed | RequestError::BadRequest => "400 Bad Request",
+            RequestError::UriTooLong => "414 URI Too Long",
+            RequestError::HeadersTooLarge => "431 Request Header Fields Too Large",
+            RequestError::VersionNotSupported => "505 HTTP Version Not Supported",
+        }
+    }
+
+    /// The response to send back, or None if the client is gone.
+    pub fn response(&self) -> Option<Vec<u8>> {
+        if *self == RequestError::Closed {
+            return None;
+        }
+        Some(
+            format!(
+                "\
+HTTP/1.1 {}\r\n\
+Cache-Control: no-cache\r\n\
+Connection: close\r\n\
+Content-Length: 0\r\n\
+\r\n",
+                self.status()
+            )
+            .into_bytes(),
+        )
+    }
+}
+
+impl Request {
+    /// Parses a request head, without the empty line that terminates it.
+    pub fn parse(head: &[u8]) -> Result<Request, RequestError> {
+        let head = from_utf8(head).map_err(|_| RequestError::BadRequest)?;
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().ok_or(RequestError::BadRequest)?;
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+                (method, target, version)
+            }
+            _ => return Err(RequestError::BadRequest),
+        };
+        if !is_token(method) || target.is_empty() || !target.bytes().all(is_target_char) {
+            return Err(RequestError::BadRequest);
+        }
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            _ if is_version(version) => return Err(RequestError::VersionNotSupported),
+            _ => return Err(RequestError::BadRequest),
+        };
+        let mut headers = Vec::new();
+        for line in lines {
+            // obsolete line folding (and any other leading whitespace) is rejected
+            let (name, value) = line.split_once(':').ok_or(RequestError::BadRequest)?;
+            if !is_token(name) {
+                return Err(RequestError::BadRequest);
+            }
+            let value = value.trim_matches(|it| it == ' ' || it == '\t');
+            if value.bytes().any(|it| it.is_ascii_control() && it != b'\t') {
+                return Err(RequestError::BadRequest);
+            }
+            headers.push((name.to_ascii_lowercase(), value.to_string()));
+        }
+        let headers = Headers(headers);
+        if version == Version::Http11 && headers.get_all("host").count() != 1 {
+            return Err(RequestError::BadRequest);
+        }
+        Ok(Request {
+            method: Method::parse(method),
+            target: target.to_string(),
+            version,
+            headers,
+        })
+    }
+
+    /// Returns the length of the body announced by the Content-Length header.
+    /// Conflicting lengths and chunked bodies are rejected.
+    pub fn content_length(&self) -> Result<Option<usize>, RequestError> {
+        if self.headers.get("transfer-encoding").is_some() {
+            return Err(RequestError::BadRequest);
+        }
+        let mut length = None;
+        for value in self.headers.get_all("content-length") {
+            if value.is_empty() || !value.bytes().all(|it| it.is_ascii_digit()) {
+                return Err(RequestError::BadRequest);
+            }
+            let value = value.parse().map_err(|_| RequestError::BadRequest)?;
+            if length.map_or(false, |it| it != value) {
+                return Err(RequestError::BadRequest);
+            }
+            length = Some(value);
+        }
+        Ok(length)
+    }
+}
+
+/// Reads and parses a request head from the stream.
+/// Returns the request, and the bytes that were read past the head (the start of the body).
+/// Reading stops as soon as a limit is exceeded, without waiting for the rest of the request.
+pub async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    limits: &Limits,
+) -> Result<(Request, Vec<u8>), RequestError> {
+    let mut buf = Vec::with_capacity(CHUNK_SIZE);
+    let mut header_count = 0;
+    let mut line_start = 0;
+    loop {
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|_| RequestError::Closed)?;
+        if n == 0 {
+            return Err(RequestError::Closed);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        while let Some(pos) = buf[line_start..].windows(2).position(|it| it == b"\r\n") {
+            let line_end = line_start + pos;
+            if line_start == 0 {
+                if line_end > limits.request_line {
+                    return Err(RequestError::UriTooLong);
+                }
+            } else if line_end == line_start {
+                if line_end > limits.head {
+                    return Err(RequestError::HeadersTooLarge);
+                }
+                let request = Request::parse(&buf[..line_start.saturating_sub(2)])?;
+                return Ok((request, buf[line_end + 2..].to_vec()));
+            } else {
+                header_count += 1;
+                if header_count > limits.headers {
+                    return Err(RequestError::HeadersTooLarge);
+                }
+            }
+            line_start = line_end + 2;
+        }
+        if line_start == 0 && buf.len() > limits.request_line + 1 {
+            return Err(RequestError::UriTooLong);
+        }
+        if buf.len() > limits.head + 3 {
+            return Err(RequestError::HeadersTooLarge);
+        }
+    }
+}
+
+// tchar from RFC 9110
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|it| it.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&it))
+}
+
+fn is_target_char(byte: u8) -> bool {
+    byte.is_ascii_graphic()
+}
+
+fn is_version(value: &str) -> bool {
+    match value.strip_prefix("HTTP/").map(|it| it.as_bytes()) {
+        Some([major, b'.', minor]) => major.is_ascii_digit() && minor.is_ascii_digit(),
+        Some([major]) => major.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::request::{read_request, Limits, Method, Request, RequestError, Version};
+
+    fn read(bytes: &[u8], limits: &Limits) -> Result<(Request, Vec<u8>), RequestError> {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut stream = bytes;
+                read_request(&mut stream, limits).await
+            })
+    }
+
+    #[test]
+    fn parse() {
+        let (request, rest) = read(
+            b"GET /a/b?c=d HTTP/1.1\r\nHost: cdn.packurl.net\r\nIF-None-Match:  \"x\" \r\n\r\nbody",
+            &Limits::default(),
+        )
+        .unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.target, "/a/b?c=d");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("if-none-match"), Some("\"x\""));
+        assert_eq!(request.headers.get("Host"), Some("cdn.packurl.net"));
+        assert_eq!(rest, b"body");
+        let (request, _) = read(b"BREW / HTTP/1.0\r\n\r\n", &Limits::default()).unwrap();
+        assert_eq!(request.method, Method::Other("BREW".to_string()));
+        assert_eq!(request.version, Version::Http10);
+    }
+
+    #[test]
+    fn errors() {
+        let limits = Limits::default();
+        let error = |bytes: &[u8]| read(bytes, &limits).unwrap_err();
+        assert_eq!(error(b""), RequestError::Closed);
+        assert_eq!(
+            error(b"GET / HTTP/1.1\r\nHost: a\r\n"),
+            RequestError::Closed
+        );
+        assert_eq!(error(b"GET / HTTP/1.1\r\n\r\n"), RequestError::BadRequest);
+        assert_eq!(
+            error(b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            RequestError::BadRequest
+        );
+        assert_eq!(
+            error(b"GET / HTTP/1.1\r\n Host: a\r\n\r\n"),
+            RequestError::BadRequest
+        );
+        assert_eq!(
+            error(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"),
+            RequestError::BadRequest
+        );
+        assert_eq!(error(b"GET / FTP/1.1\r\n\r\n"), RequestError::BadRequest);
+        assert_eq!(
+            error(b"GET / HTTP/2.0\r\n\r\n"),
+            RequestError::VersionNotSupported
+        );
+        let long = format!("GET /{} HTTP/1.1\r\n", "a".repeat(limits.request_line));
+        assert_eq!(error(long.as_bytes()), RequestError::UriTooLong);
+        // the request line limit applies before the end of the line is received
+        assert_eq!(error(&long.as_bytes()[..2060]), RequestError::UriTooLong);
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "a: b\r\n".repeat(65));
+        assert_eq!(error(many.as_bytes()), RequestError::HeadersTooLarge);
+        let large = format!("GET / HTTP/1.1\r\na: {}\r\n\r\n", "b".repeat(limits.head));
+        assert_eq!(error(large.as_bytes()), RequestError::HeadersTooLarge);
+    }
+
+    #[test]
+    fn content_length() {
+        let request = |head: &[u8]| Request::parse(head).unwrap();
+        assert_eq!(
+            request(b"POST / HTTP/1.0\r\nContent-Length: 12").content_length(),
+            Ok(Some(12))
+        );
+        assert_eq!(request(b"POST / HTTP/1.0").content_length(), Ok(None));
+        assert!(
+            request(b"POST / HTTP/1.0\r\nContent-Length: 1\r\nContent-Length: 2")
+                .content_length()
+                .is_err()
+        );
+        assert!(request(b"POST / HTTP/1.0\r\nContent-Length: -1")
+            .content_length()
+            .is_err());
+        assert!(request(b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked")
+            .content_length()
+            .is_err());
+    }
+
+    // xorshift, so that failures are reproducible
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes() {
+        let mut random = Random(0x9e3779b97f4a7c15);
+        let alphabet = b"GET HTTP/1.1\r\n:Host \t\x00\xff/a";
+        for _ in 0..2000 {
+            let len = random.below(300);
+            let bytes: Vec<u8> = (0..len)
+                .map(|_| alphabet[random.below(alphabet.len())])
+                .collect();
+            let limits = Limits {
+                request_line: 1 + random.below(64),
+                head: 1 + random.below(256),
+                headers: random.below(8),
+            };
+            if let Ok((request, rest)) = read(&bytes, &limits) {
+                assert!(!request.target.is_empty());
+                assert!(rest.len() < bytes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_mutations() {
+        let mut random = Random(0x2545f4914f6cdd1d);
+        let valid =
+            b"GET /index.html HTTP/1.1\r\nHost: cdn.packurl.net\r\nRange: bytes=0-1\r\n\r\n";
+        for _ in 0..2000 {
+            let mut bytes = valid.to_vec();
+            for _ in 0..1 + random.below(4) {
+                let pos = random.below(bytes.len());
+                match random.below(3) {
+                    0 => bytes[pos] = random.next() as u8,
+                    1 => {
+                        bytes.remove(pos);
+                    }
+                    _ => bytes.insert(pos, random.next() as u8),
+                }
+            }
+            if let Ok((request, _)) = read(&bytes, &Limits::default()) {
+                // a request that parses is made of valid parts
+                assert!(request.target.bytes().all(|it| it.is_ascii_graphic()));
+                assert_eq!(request.headers.get_all("host").count(), 1);
+            }
+        }
+    }
+}
@@ -1,16 +1,10 @@
-use crate::cdn::Cache;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::cdn::{stats, Cache};
+use crate::deploy::last_report;
+use crate::http::{read_request, Limits, Method};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio_rustls::server::TlsStream;
 
-const OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
-Cache-Control: no-cache\r\n\
-Connection: close\r\n\
-Content-Type: text/plain\r\n\
-Content-Length: 6\r\n\
-\r\n\
-local\
-\n";
 const NOT_FOUND_RESPONSE: &[u8] = b"HTTP/1.1 404 Not Found\r\n\
 Cache-Control: no-cache\r\n\
 Connection: close\r\n\
@@ -18,32 +12,71 @@ Content-Length: 0\r\n\
 \r\n";
 
 pub async fn handle_local_request(stream: &mut TlsStream<TcpStream>) {
-    let mut buf = [0; 22];
-    if stream.read_exact(&mut buf).await.is_ok() {
-        match &buf {
-            b"GET /update HTTP/1.1\r\n" => {
-                if let Err(err) = Cache::update().await {
-                    let trace = format!("{:?}", err);
-                    let body = trace.as_bytes();
-                    let text = format!(
-                        "\
-HTTP/1.1 500 Internal Server Error\r\n\
+    let request = match read_request(stream, &Limits::default()).await {
+        Ok((request, _)) => request,
+        Err(err) => {
+            if let Some(response) = err.response() {
+                let _ = stream.write_all(&response).await;
+            }
+            return;
+        }
+    };
+    match (&request.method, request.target.as_str()) {
+        (Method::Get, "/update") => {
+            let (status, body) = match Cache::update().await {
+                Ok(summary) => ("200 OK", summary.to_string()),
+                Err(err) => ("500 Internal Server Error", format!("{:?}", err)),
+            };
+            let text = format!(
+                "\
+HTTP/1.1 {}\r\n\
 Cache-Control: no-store\r\n\
 Connection: close\r\n\
 Content-Type: text/plain\r\n\
 Content-Length: {}\r\n\
 \r\n",
-                        body.len()
-                    );
-                    let _ = stream.write_all(text.as_bytes()).await;
-                    let _ = stream.write_all(body).await;
-                } else {
-                    let _ = stream.write_all(OK_RESPONSE).await;
-                }
-            }
-            _ => {
-                let _ = stream.write_all(NOT_FOUND_RESPONSE).await;
-            }
+                status,
+                body.len()
+            );
+            let _ = stream.write_all(text.as_bytes()).await;
+            let _ = stream.write_all(body.as_bytes()).await;
+        }
+        (Method::Get, "/stats") => {
+            let body = stats();
+            let header = format!(
+                "\
+HTTP/1.1 200 OK\r\n\
+Cache-Control: no-store\r\n\
+Connection: close\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: {}\r\n\
+\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(header.as_bytes()).await;
+            let _ = stream.write_all(body.as_bytes()).await;
+        }
+        (Method::Get, "/deploy") => {
+            let (status, body) = match last_report() {
+                Some(report) => ("200 OK", report),
+                None => ("404 Not Found", "No deployment since startup\n".to_string()),
+            };
+            let header = format!(
+                "\
+HTTP/1.1 {}\r\n\
+Cache-Control: no-store\r\n\
+Connection: close\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: {}\r\n\
+\r\n",
+                status,
+                body.len()
+            );
+            let _ = stream.write_all(header.as_bytes()).await;
+            let _ = stream.write_all(body.as_bytes()).await;
+        }
+        _ => {
+            let _ = stream.write_all(NOT_FOUND_RESPONSE).await;
         }
     }
 }
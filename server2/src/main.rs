@@ -1,37 +1,44 @@
 mod acme;
 mod apex;
 mod cdn;
+mod deploy;
 mod domains;
+mod http;
 mod local;
 mod log;
 mod tls;
 use crate::acme::handle_acme_request;
-use crate::apex::{handle_apex_request, handle_redirect_to_https};
-use crate::cdn::{handle_cdn_request, Cache};
+use crate::apex::{handle_apex_request, handle_redirect_to_https, WellKnown};
+use crate::cdn::{handle_cdn_request, Cache, Site};
 use crate::local::handle_local_request;
-use acme::Account;
+use acme::{Account, Ocsp};
 use colored::Colorize;
-use domains::{APEX, CDN, LOCALHOST, LOCALHOST_IPV4, LOCALHOST_IPV6, WWW};
+use domains::{host_or_default, APEX, LOCALHOST, LOCALHOST_IPV4, LOCALHOST_IPV6, WWW};
 use log::LogLevel;
 use rustls::server::Acceptor;
-use std::io::Result;
+use rustls::AlertDescription;
+use std::io::{Error, Result};
 use std::net::Ipv6Addr;
 use std::sync::Arc;
 use std::time::Duration;
-use tls::{config, ALPN_ACME_TLS};
+use tls::{config, fatal_alert, offered_versions, ALPN_ACME_TLS};
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio_rustls::LazyConfigAcceptor;
 
 const FIVE_DAYS: u64 = 86_400 * 5;
+// a full TLS record, so that large ClientHellos (post-quantum key shares) aren't cut short
+const CLIENT_HELLO_PEEK_SIZE: usize = 5 + 16_384;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     LogLevel::init();
-    Cache::init();
+    Cache::init()?;
+    WellKnown::init()?;
     Account::init()
         .await?
         .auto_renew_certificate_every(Duration::from_secs(FIVE_DAYS));
+    Ocsp::init();
 
     let tls_config = config()?;
 
@@ -66,7 +73,9 @@ async fn main() -> Result<()> {
     println!("{}", "Listening on:".green());
     println!("{}", format!("https://{}", APEX).cyan().underline());
     println!("{}", format!("https://{}", WWW).cyan().underline());
-    println!("{}", format!("https://{}", CDN).cyan().underline());
+    for site in Site::all() {
+        println!("{}", format!("https://{}", site.host).cyan().underline());
+    }
     println!("{}", format!("https://{}", LOCALHOST).cyan().underline());
 
     loop {
@@ -84,82 +93,95 @@ async fn main() -> Result<()> {
                         format!("{}", remote_addr.ip()).purple()
                     );
                 });
-                let acceptor = LazyConfigAcceptor::new(Acceptor::default(), tcp);
                 let config = tls_config.clone();
                 let future = async move {
+                    let mut hello = vec![0u8; CLIENT_HELLO_PEEK_SIZE];
+                    let len = tcp.peek(&mut hello).await.unwrap_or(0);
+                    hello.truncate(len);
+                    let log_handshake_failure = |err: Error| {
+                        LogLevel::Warning.log(|| {
+                            println!("{}", "TLS handshake failed".red());
+                            if let Some(versions) = offered_versions(&hello) {
+                                println!("Client offered {}", versions.join(", ").purple());
+                            }
+                            println!("{:?}", err);
+                        });
+                    };
+                    let acceptor = LazyConfigAcceptor::new(Acceptor::default(), tcp);
                     match acceptor.await {
                         Ok(start_handshake) => {
                             let client_hello = start_handshake.client_hello();
-                            match client_hello.server_name() {
-                                Some(sni) => {
-                                    LogLevel::Debug.log(|| {
-                                        println!(
-                                            "{}",
-                                            format!("TLS SNI extension: {}", sni.purple())
-                                        );
-                                    });
-                                    let server_name = sni.clone().to_string();
-                                    if client_hello
-                                        .alpn()
-                                        .and_then(|mut it| it.find(|&it| it == ALPN_ACME_TLS))
-                                        .is_some()
-                                    {
-                                        LogLevel::Info.log(|| {
-                                            println!(
-                                                "{}",
-                                                format!(
-                                                    "Responding to ACME Challenge for {}",
-                                                    server_name.purple()
-                                                )
-                                            );
-                                        });
-                                        let mut acme_config = config.as_ref().clone();
-                                        acme_config.alpn_protocols = vec![ALPN_ACME_TLS.to_vec()];
-                                        match start_handshake
-                                            .into_stream(Arc::new(acme_config))
-                                            .await
-                                        {
-                                            Ok(mut stream) => {
-                                                handle_acme_request(&mut stream).await;
-                                                let _ = stream.shutdown().await;
+                            let sni = client_hello.server_name().map(|it| it.to_string());
+                            match sni.as_ref() {
+                                Some(sni) => LogLevel::Debug.log(|| {
+                                    println!("{}", format!("TLS SNI extension: {}", sni.purple()));
+                                }),
+                                None => LogLevel::Debug
+                                    .log(|| println!("{}", "TLS SNI extension is missing".red())),
+                            }
+                            if sni.is_some()
+                                && client_hello
+                                    .alpn()
+                                    .and_then(|mut it| it.find(|&it| it == ALPN_ACME_TLS))
+                                    .is_some()
+                            {
+                                LogLevel::Info.log(|| {
+                                    println!(
+                                        "{}",
+                                        format!(
+                                            "Responding to ACME Challenge for {}",
+                                            sni.as_deref().unwrap_or_default().purple()
+                                        )
+                                    );
+                                });
+                                let mut acme_config = config.as_ref().clone();
+                                acme_config.alpn_protocols = vec![ALPN_ACME_TLS.to_vec()];
+                                match start_handshake.into_stream(Arc::new(acme_config)).await {
+                                    Ok(mut stream) => {
+                                        handle_acme_request(&mut stream).await;
+                                        let _ = stream.shutdown().await;
+                                    }
+                                    Err(err) => log_handshake_failure(err),
+                                }
+                            } else if config
+                                .cert_resolver
+                                .resolve(start_handshake.client_hello())
+                                .is_none()
+                            {
+                                // Without a usable host, the certificate resolver finds nothing.
+                                // tokio-rustls drops the connection without the alert queued by
+                                // rustls, so it is sent here before closing the connection.
+                                let alert = match sni {
+                                    Some(_) => fatal_alert(AlertDescription::UnrecognisedName),
+                                    None => fatal_alert(AlertDescription::HandshakeFailure),
+                                };
+                                if let Err((err, mut tcp)) =
+                                    start_handshake.into_stream(config).into_fallible().await
+                                {
+                                    let _ = tcp.write_all(&alert).await;
+                                    let _ = tcp.shutdown().await;
+                                    log_handshake_failure(err);
+                                }
+                            } else {
+                                match start_handshake.into_stream(config).await {
+                                    Ok(mut stream) => {
+                                        match host_or_default(sni.as_deref()) {
+                                            Some(LOCALHOST | LOCALHOST_IPV4 | LOCALHOST_IPV6) => {
+                                                handle_local_request(&mut stream).await;
                                             }
-                                            Err(err) => {
-                                                LogLevel::Warning.log(|| {
-                                                    println!("{}", "TLS handshake failed".red());
-                                                    println!("{:?}", err);
-                                                });
+                                            Some(APEX | WWW) => {
+                                                handle_apex_request(&mut stream).await;
                                             }
-                                        }
-                                    } else {
-                                        match start_handshake.into_stream(config).await {
-                                            Ok(mut stream) => {
-                                                match server_name.as_str() {
-                                                    LOCALHOST | LOCALHOST_IPV4 | LOCALHOST_IPV6 => {
-                                                        handle_local_request(&mut stream).await;
-                                                    }
-                                                    CDN => {
-                                                        handle_cdn_request(&mut stream).await;
-                                                    }
-                                                    APEX | WWW => {
-                                                        handle_apex_request(&mut stream).await;
-                                                    }
-                                                    _ => {}
+                                            Some(host) => {
+                                                if let Some(site) = Site::for_host(host) {
+                                                    handle_cdn_request(&mut stream, site).await;
                                                 }
-                                                let _ = stream.shutdown().await;
-                                            }
-                                            Err(err) => {
-                                                LogLevel::Warning.log(|| {
-                                                    println!("{}", "TLS handshake failed".red());
-                                                    println!("{:?}", err);
-                                                });
                                             }
+                                            None => {}
                                         }
+                                        let _ = stream.shutdown().await;
                                     }
-                                }
-                                None => {
-                                    LogLevel::Debug.log(|| {
-                                        println!("{}", "TLS SNI extension is missing".red())
-                                    });
+                                    Err(err) => log_handshake_failure(err),
                                 }
                             }
                         }
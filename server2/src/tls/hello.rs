@@ -0,0 +1,153 @@
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// Extracts the protocol versions offered in a raw TLS ClientHello record.
+/// The supported_versions extension takes precedence over the legacy version field.
+/// Returns None if the bytes don't look like a ClientHello, or if they are cut before the
+/// extension could be found (the legacy field of a TLS 1.3 client would say TLSv1.2).
+pub fn offered_versions(bytes: &[u8]) -> Option<Vec<&'static str>> {
+    if bytes.len() < 5 || bytes[0] != HANDSHAKE_RECORD {
+        return None;
+    }
+    let hello = &bytes[5..];
+    if hello.len() < 4 || hello[0] != CLIENT_HELLO {
+        return None;
+    }
+    let hello = &hello[4..];
+    let legacy_version = u16::from_be_bytes([*hello.first()?, *hello.get(1)?]);
+    let versions = supported_versions(hello)?.unwrap_or_else(|| vec![legacy_version]);
+    Some(
+        versions
+            .into_iter()
+            .filter(|it| it & 0x0f0f != 0x0a0a)
+            .map(version_name)
+            .collect(),
+    )
+}
+
+// Returns Some(None) when the extension is missing, and None when the bytes are truncated.
+fn supported_versions(hello: &[u8]) -> Option<Option<Vec<u16>>> {
+    // legacy_version + random
+    let mut pos = 2 + 32;
+    // session id
+    pos += 1 + *hello.get(pos)? as usize;
+    // cipher suites
+    pos += 2 + u16::from_be_bytes([*hello.get(pos)?, *hello.get(pos + 1)?]) as usize;
+    // compression methods
+    pos += 1 + *hello.get(pos)? as usize;
+    let len = u16::from_be_bytes([*hello.get(pos)?, *hello.get(pos + 1)?]) as usize;
+    pos += 2;
+    let available = hello.get(pos..)?;
+    let complete = available.len() >= len;
+    let mut extensions = &available[..len.min(available.len())];
+    while extensions.len() >= 4 {
+        let typ = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        let data = match extensions.get(4..4 + len) {
+            Some(data) => data,
+            None => break,
+        };
+        if typ == SUPPORTED_VERSIONS {
+            let list = data.get(1..1 + *data.first()? as usize)?;
+            return Some(Some(
+                list.chunks_exact(2)
+                    .map(|it| u16::from_be_bytes([it[0], it[1]]))
+                    .collect(),
+            ));
+        }
+        extensions = &extensions[4 + len..];
+    }
+    if complete {
+        Some(None)
+    } else {
+        None
+    }
+}
+
+fn version_name(version: u16) -> &'static str {
+    match version {
+        0x0304 => "TLSv1.3",
+        0x0303 => "TLSv1.2",
+        0x0302 => "TLSv1.1",
+        0x0301 => "TLSv1.0",
+        0x0300 => "SSLv3",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::hello::offered_versions;
+
+    fn client_hello(legacy_version: [u8; 2], extensions: &[u8]) -> Vec<u8> {
+        let mut body = legacy_version.to_vec();
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+        let mut handshake = vec![0x01, 0, 0, 0];
+        handshake[2..4].copy_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01, 0, 0];
+        record[3..5].copy_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn legacy_version() {
+        let hello = client_hello([0x03, 0x02], &[]);
+        assert_eq!(offered_versions(&hello), Some(vec!["TLSv1.1"]));
+    }
+
+    #[test]
+    fn supported_versions_extension() {
+        let hello = client_hello(
+            [0x03, 0x03],
+            &[
+                0x00, 0x00, 0x00, 0x00, // empty server_name
+                0x00, 0x2b, 0x00, 0x07, 0x06, 0x3a, 0x3a, 0x03, 0x04, 0x03, 0x03,
+            ],
+        );
+        assert_eq!(offered_versions(&hello), Some(vec!["TLSv1.3", "TLSv1.2"]));
+    }
+
+    #[test]
+    fn truncated() {
+        let hello = client_hello(
+            [0x03, 0x03],
+            &[
+                0x00, 0x33, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00, // key_share
+                0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04,
+            ],
+        );
+        assert_eq!(offered_versions(&hello), Some(vec!["TLSv1.3"]));
+        // cut in the middle of the key share, before supported_versions
+        assert_eq!(offered_versions(&hello[..hello.len() - 10]), None);
+        // cut after supported_versions, in the extensions that follow
+        let hello = client_hello(
+            [0x03, 0x03],
+            &[
+                0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04, // supported_versions
+                0x00, 0x33, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00,
+            ],
+        );
+        assert_eq!(
+            offered_versions(&hello[..hello.len() - 4]),
+            Some(vec!["TLSv1.3"])
+        );
+        // without the extension, the legacy version is only used if nothing is missing
+        let hello = client_hello([0x03, 0x03], &[0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(offered_versions(&hello), Some(vec!["TLSv1.2"]));
+        assert_eq!(offered_versions(&hello[..hello.len() - 1]), None);
+    }
+
+    #[test]
+    fn not_a_client_hello() {
+        assert_eq!(offered_versions(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(offered_versions(&[0x16, 0x03]), None);
+    }
+}
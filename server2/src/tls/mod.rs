@@ -1,22 +1,162 @@
+pub use hello::offered_versions;
+use lazy_static::lazy_static;
 use resolver::CertResolver;
-use rustls::version::TLS13;
-use rustls::ServerConfig;
+use rustls::version::{TLS12, TLS13};
+use rustls::{
+    AlertDescription, ServerConfig, SupportedCipherSuite, SupportedKxGroup,
+    SupportedProtocolVersion, ALL_CIPHER_SUITES, ALL_KX_GROUPS,
+};
+use std::env::var;
 use std::io::{Error, ErrorKind, Result};
 use std::sync::Arc;
 
+mod hello;
 mod resolver;
+mod source;
 
 pub const ALPN_HTTP1: &'static [u8] = b"http/1.1";
 pub const ALPN_ACME_TLS: &'static [u8] = b"acme-tls/1";
 
+lazy_static! {
+    static ref ENABLE_TLS12: bool = var("XDG_TLS12")
+        .map(|it| it == "1" || it.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    static ref CIPHER_SUITES: Option<String> = var("XDG_TLS_CIPHER_SUITES").ok();
+    static ref KX_GROUPS: Option<String> = var("XDG_TLS_KX_GROUPS").ok();
+}
+
+/// Returns a fatal alert record, for the handshakes that are rejected before rustls takes over.
+pub fn fatal_alert(description: AlertDescription) -> [u8; 7] {
+    // alert content type, TLS 1.2 record version, length, fatal level, description
+    [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, description.get_u8()]
+}
+
 pub fn config() -> Result<Arc<ServerConfig>> {
+    let versions: &[&SupportedProtocolVersion] = if *ENABLE_TLS12 {
+        &[&TLS13, &TLS12]
+    } else {
+        &[&TLS13]
+    };
+    let resolver = Arc::new(CertResolver::try_new()?);
+    resolver.watch_pem_files();
     let mut config = ServerConfig::builder()
-        .with_safe_default_cipher_suites()
-        .with_safe_default_kx_groups()
-        .with_protocol_versions(&[&TLS13])
+        .with_cipher_suites(&cipher_suites(versions, CIPHER_SUITES.as_deref())?)
+        .with_kx_groups(&kx_groups(KX_GROUPS.as_deref())?)
+        .with_protocol_versions(versions)
         .map_err(|err| Error::new(ErrorKind::Unsupported, err))?
         .with_no_client_auth()
-        .with_cert_resolver(Arc::new(CertResolver::try_new()?));
+        .with_cert_resolver(resolver);
     config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
     Ok(Arc::new(config))
 }
+
+// rustls only implements ECDHE key exchange with AEAD ciphers, so every suite it knows about is
+// acceptable; the list is only narrowed down to the enabled protocol versions and configuration.
+fn cipher_suites(
+    versions: &[&SupportedProtocolVersion],
+    names: Option<&str>,
+) -> Result<Vec<SupportedCipherSuite>> {
+    let enabled = |suite: &SupportedCipherSuite| {
+        versions
+            .iter()
+            .any(|&it| it.version == suite.version().version)
+    };
+    match names {
+        None => Ok(ALL_CIPHER_SUITES
+            .iter()
+            .filter(|&it| enabled(it))
+            .copied()
+            .collect()),
+        Some(names) => names
+            .split(',')
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+            .filter_map(|name| {
+                match ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|&it| format!("{:?}", it.suite()).eq_ignore_ascii_case(name))
+                {
+                    Some(suite) if enabled(suite) => Some(Ok(*suite)),
+                    Some(_) => None,
+                    None => Some(Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Unknown cipher suite {}", name),
+                    ))),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn kx_groups(names: Option<&str>) -> Result<Vec<&'static SupportedKxGroup>> {
+    match names {
+        None => Ok(ALL_KX_GROUPS.to_vec()),
+        Some(names) => names
+            .split(',')
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+            .map(|name| {
+                ALL_KX_GROUPS
+                    .iter()
+                    .find(|&it| format!("{:?}", it.name).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Unknown key exchange group {}", name),
+                        )
+                    })
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::{cipher_suites, fatal_alert, kx_groups};
+    use rustls::version::{TLS12, TLS13};
+    use rustls::{AlertDescription, CipherSuite, NamedGroup};
+
+    #[test]
+    fn cipher_suite_names() {
+        let all = cipher_suites(&[&TLS13, &TLS12], None).unwrap();
+        assert_eq!(all.len(), 9);
+        let tls13 = cipher_suites(&[&TLS13], None).unwrap();
+        assert_eq!(tls13.len(), 3);
+        let names = " tls13_aes_256_gcm_sha384,,TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256 ";
+        let suites = cipher_suites(&[&TLS13, &TLS12], Some(names)).unwrap();
+        assert_eq!(
+            suites.iter().map(|it| it.suite()).collect::<Vec<_>>(),
+            vec![
+                CipherSuite::TLS13_AES_256_GCM_SHA384,
+                CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+            ]
+        );
+        // suites of disabled versions are left out, unknown ones are rejected
+        let suites = cipher_suites(&[&TLS13], Some(names)).unwrap();
+        assert_eq!(suites.len(), 1);
+        assert!(cipher_suites(&[&TLS13], Some("TLS_RSA_WITH_RC4_128_SHA")).is_err());
+        assert!(cipher_suites(&[&TLS13], Some("aes")).is_err());
+    }
+
+    #[test]
+    fn kx_group_names() {
+        assert_eq!(kx_groups(None).unwrap().len(), 3);
+        let groups = kx_groups(Some("secp384r1, X25519,")).unwrap();
+        assert_eq!(
+            groups.iter().map(|it| it.name).collect::<Vec<_>>(),
+            vec![NamedGroup::secp384r1, NamedGroup::X25519]
+        );
+        assert!(kx_groups(Some("X25519MLKEM768")).is_err());
+        assert!(kx_groups(Some("ffdhe2048")).is_err());
+    }
+
+    #[test]
+    fn alerts() {
+        assert_eq!(
+            fatal_alert(AlertDescription::UnrecognisedName),
+            [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 112]
+        );
+        assert_eq!(fatal_alert(AlertDescription::HandshakeFailure)[6], 40);
+    }
+}
@@ -1,115 +1,199 @@
 use crate::acme::{get_certificate, get_challenge_key};
-use crate::domains::{ACME_DOMAINS, SELF_SIGNED_DOMAINS};
+use crate::domains::DEFAULT_HOST;
+use crate::tls::source::{load_pem, self_signed, CertSource};
 use crate::tls::ALPN_ACME_TLS;
 use crate::LogLevel;
+use arc_swap::ArcSwap;
 use colored::Colorize;
-use rcgen::generate_simple_self_signed;
 use rustls::server::{ClientHello, ResolvesServerCert};
-use rustls::sign::{any_supported_type, CertifiedKey};
-use rustls::{Certificate, PrivateKey};
-use std::io::{Error, ErrorKind};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-use tokio::time::Instant;
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::fs::metadata;
+use tokio::time::interval;
 
-const MAX_DURATION: Duration = Duration::from_secs(90_000); // a little over 1 day
+const PEM_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+enum DomainCertificate {
+    Acme,
+    Static(Arc<CertifiedKey>),
+    Pem(Arc<PemFile>),
+}
+
+struct PemFile {
+    path: PathBuf,
+    key: ArcSwap<CertifiedKey>,
+    modified: Mutex<Option<SystemTime>>,
+}
 
 pub struct CertResolver {
-    acme: RwLock<Option<(Arc<CertifiedKey>, Instant)>>,
-    self_signed: Arc<CertifiedKey>,
+    domains: HashMap<String, DomainCertificate>,
 }
 
 impl CertResolver {
     pub fn try_new() -> Result<Self, Error> {
-        LogLevel::Info.log(|| println!("Creating self-signed certificates"));
-        let self_signed = generate_simple_self_signed(SELF_SIGNED_DOMAINS)
-            .map_err(|err| Error::new(ErrorKind::Unsupported, err))?;
-        let private_key = PrivateKey(self_signed.serialize_private_key_der());
-        let key = CertifiedKey {
-            cert: vec![Certificate(
-                self_signed
-                    .serialize_der()
-                    .map_err(|err| Error::new(ErrorKind::Unsupported, err))?,
-            )],
-            key: any_supported_type(&private_key)
-                .map_err(|err| Error::new(ErrorKind::Unsupported, err))?,
-            ocsp: None,
-            sct_list: None,
+        let sources = CertSource::all()?;
+        let self_signed_domains: Vec<String> = sources
+            .iter()
+            .filter(|(_, source)| source == &CertSource::SelfSigned)
+            .map(|(domain, _)| domain.to_string())
+            .collect();
+        let self_signed = if self_signed_domains.is_empty() {
+            None
+        } else {
+            LogLevel::Info.log(|| println!("Creating self-signed certificates"));
+            Some(Arc::new(self_signed(self_signed_domains)?))
         };
-        Ok(CertResolver {
-            acme: RwLock::new(None),
-            self_signed: Arc::new(key),
-        })
+        let mut pem_files: HashMap<PathBuf, Arc<PemFile>> = HashMap::new();
+        let mut domains = HashMap::new();
+        for (domain, source) in sources {
+            let certificate = match source {
+                CertSource::Acme => DomainCertificate::Acme,
+                CertSource::SelfSigned => {
+                    DomainCertificate::Static(self_signed.clone().expect("self-signed certificate"))
+                }
+                CertSource::Pem(path) => {
+                    let file = match pem_files.get(&path) {
+                        Some(file) => file.clone(),
+                        None => {
+                            LogLevel::Info.log(|| {
+                                println!(
+                                    "Loading certificate from {}",
+                                    format!("{}", path.display()).yellow()
+                                )
+                            });
+                            let modified = std::fs::metadata(&path)?.modified().ok();
+                            let file = Arc::new(PemFile {
+                                key: ArcSwap::from_pointee(load_pem(&path)?),
+                                modified: Mutex::new(modified),
+                                path: path.clone(),
+                            });
+                            pem_files.insert(path, file.clone());
+                            file
+                        }
+                    };
+                    DomainCertificate::Pem(file)
+                }
+            };
+            domains.insert(domain, certificate);
+        }
+        if let Some(host) = DEFAULT_HOST.as_ref() {
+            if !domains.contains_key(host) {
+                LogLevel::Warning.log(|| {
+                    println!(
+                        "{}",
+                        format!("No certificate for default host {}", host).red()
+                    )
+                });
+            }
+        }
+        Ok(CertResolver { domains })
+    }
+
+    /// Periodically checks the PEM certificate files for changes and reloads them.
+    pub fn watch_pem_files(self: &Arc<Self>) {
+        let mut files: Vec<Arc<PemFile>> = Vec::new();
+        for certificate in self.domains.values() {
+            if let DomainCertificate::Pem(file) = certificate {
+                if !files.iter().any(|it| Arc::ptr_eq(it, file)) {
+                    files.push(file.clone());
+                }
+            }
+        }
+        if files.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = interval(PEM_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                for file in files.iter() {
+                    file.reload_if_modified().await;
+                }
+            }
+        });
+    }
+}
+
+impl PemFile {
+    async fn reload_if_modified(&self) {
+        let modified = match metadata(&self.path).await.and_then(|it| it.modified()) {
+            Ok(modified) => Some(modified),
+            Err(err) => {
+                LogLevel::Warning.log(|| {
+                    println!(
+                        "{}",
+                        format!("Failed to check certificate {}", self.path.display()).red()
+                    );
+                    println!("{:?}", err);
+                });
+                return;
+            }
+        };
+        let mut lock = match self.modified.lock() {
+            Ok(lock) => lock,
+            Err(_) => return,
+        };
+        if *lock == modified {
+            return;
+        }
+        match load_pem(&self.path) {
+            Ok(key) => {
+                self.key.store(Arc::new(key));
+                *lock = modified;
+                LogLevel::Info.log(|| {
+                    println!(
+                        "{}",
+                        format!("Reloaded certificate {}", self.path.display()).green()
+                    )
+                });
+            }
+            Err(err) => LogLevel::Warning.log(|| {
+                println!(
+                    "{}",
+                    format!("Failed to reload certificate {}", self.path.display()).red()
+                );
+                println!("{:?}", err);
+            }),
+        }
     }
 }
 
 impl CertResolver {
-    fn update_certificate(&self) -> Option<Arc<CertifiedKey>> {
-        if let Some((key, instant)) = get_certificate() {
-            LogLevel::Debug.log(|| println!("Certificate found"));
-            if let Some(mut lock) = self.acme.write().ok() {
-                let key = Arc::new(key);
-                let _ = lock.replace((key.clone(), instant));
-                LogLevel::Debug.log(|| println!("Certificate loaded"));
-                Some(key)
+    fn resolve_acme(&self, client_hello: &ClientHello, sni: &str) -> Option<Arc<CertifiedKey>> {
+        if client_hello
+            .alpn()
+            .and_then(|mut it| it.find(|&it| it == ALPN_ACME_TLS))
+            .is_some()
+        {
+            LogLevel::Debug
+                .log(|| println!("Looking for unsigned certificate for {}", sni.purple()));
+            if let Some(key) = get_challenge_key(sni) {
+                LogLevel::Debug.log(|| println!("Certificate found"));
+                Some(Arc::new(key))
             } else {
-                LogLevel::Debug.log(|| println!("Certificate could not be loaded"));
+                LogLevel::Debug.log(|| println!("Certificate not found"));
                 None
             }
         } else {
-            LogLevel::Debug.log(|| println!("Certificate not found"));
-            None
+            get_certificate()
         }
     }
 }
 
 impl ResolvesServerCert for CertResolver {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        if let Some(sni) = client_hello.server_name() {
-            if ACME_DOMAINS.find(sni).is_some() {
-                if client_hello
-                    .alpn()
-                    .and_then(|mut it| it.find(|&it| it == ALPN_ACME_TLS))
-                    .is_some()
-                {
-                    LogLevel::Debug
-                        .log(|| println!("Looking for unsigned certificate for {}", sni.purple()));
-                    if let Some(key) = get_challenge_key(sni) {
-                        LogLevel::Debug.log(|| println!("Certificate found"));
-                        Some(Arc::new(key))
-                    } else {
-                        LogLevel::Debug.log(|| println!("Certificate not found"));
-                        None
-                    }
-                } else {
-                    if let Some(inner) = self.acme.read().ok() {
-                        if let Some(inner) = inner.clone() {
-                            let key = inner.0.clone();
-                            let elapsed = inner.1.elapsed();
-                            drop(inner);
-                            if elapsed > MAX_DURATION {
-                                LogLevel::Debug.log(|| {
-                                    println!("Updating issued certificate for {}", sni.purple())
-                                });
-                                let _ = self.update_certificate();
-                            }
-                            Some(key)
-                        } else {
-                            drop(inner);
-                            LogLevel::Debug.log(|| {
-                                println!("Looking for issued certificate for {}", sni.purple())
-                            });
-                            self.update_certificate()
-                        }
-                    } else {
-                        None
-                    }
-                }
-            } else {
-                Some(self.self_signed.clone())
-            }
-        } else {
-            None
+        let host = client_hello
+            .server_name()
+            .filter(|&it| self.domains.contains_key(it))
+            .or(DEFAULT_HOST.as_deref())?;
+        match self.domains.get(host)? {
+            DomainCertificate::Acme => self.resolve_acme(&client_hello, host),
+            DomainCertificate::Static(key) => Some(key.clone()),
+            DomainCertificate::Pem(file) => Some(file.key.load_full()),
         }
     }
 }
@@ -0,0 +1,259 @@
+use crate::acme::STATE_DIRECTORY;
+use crate::cdn::Site;
+use crate::domains::{acme_domains, SELF_SIGNED_DOMAINS};
+use crate::log::LogLevel;
+use colored::Colorize;
+use lazy_static::lazy_static;
+use pem::parse_many;
+use rcgen::{Certificate, CertificateParams, KeyPair, PKCS_ECDSA_P256_SHA256};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::PrivateKey;
+use std::env::var;
+use std::fs::{create_dir_all, read, read_to_string, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+lazy_static! {
+    static ref CERTIFICATES: Option<String> = var("XDG_TLS_CERTIFICATES").ok();
+}
+
+const SELF_SIGNED_KEY_FILE: &str = "self-signed.key.pem";
+
+/// Where the certificate for a domain comes from.
+///
+/// Configured with XDG_TLS_CERTIFICATES as a comma-separated list of domain=source pairs,
+/// where source is one of "acme", "self-signed" or "pem:/path/to/file.pem".
+/// Domains that are not listed keep their default source, which is self-signed for virtual hosts
+/// that aren't part of the ACME order.
+#[derive(Clone, PartialEq, Eq)]
+pub enum CertSource {
+    Acme,
+    SelfSigned,
+    Pem(PathBuf),
+}
+
+impl CertSource {
+    /// Parses the source of a domain, where "acme" is only valid for the domains of the order.
+    fn parse(domain: &str, source: &str, acme: &[String]) -> Result<Self> {
+        match source {
+            "acme" => {
+                if acme.iter().any(|it| it == domain) {
+                    Ok(CertSource::Acme)
+                } else {
+                    Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("{} is not part of the ACME order", domain),
+                    ))
+                }
+            }
+            "self-signed" => Ok(CertSource::SelfSigned),
+            _ => match source.strip_prefix("pem:") {
+                Some(path) if !path.is_empty() => Ok(CertSource::Pem(PathBuf::from(path))),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown certificate source {} for {}", source, domain),
+                )),
+            },
+        }
+    }
+
+    pub fn all() -> Result<Vec<(String, CertSource)>> {
+        let acme = acme_domains();
+        let mut sources: Vec<(String, CertSource)> = acme
+            .iter()
+            .map(|it| (it.clone(), CertSource::Acme))
+            .chain(
+                SELF_SIGNED_DOMAINS
+                    .iter()
+                    .map(|it| (it.to_string(), CertSource::SelfSigned)),
+            )
+            .collect();
+        for site in Site::all() {
+            if !sources.iter().any(|(it, _)| it == &site.host) {
+                sources.push((site.host.clone(), CertSource::SelfSigned));
+            }
+        }
+        if let Some(config) = CERTIFICATES.as_ref() {
+            Self::apply(&mut sources, config, &acme)?;
+        }
+        Ok(sources)
+    }
+
+    /// Applies the domain=source overrides of the configuration to the default sources.
+    fn apply(sources: &mut Vec<(String, CertSource)>, config: &str, acme: &[String]) -> Result<()> {
+        for entry in config
+            .split(',')
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+        {
+            let (domain, source) = entry.split_once('=').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid certificate source {}", entry),
+                )
+            })?;
+            let domain = domain.trim().to_lowercase();
+            let source = Self::parse(&domain, source.trim(), acme)?;
+            match sources.iter_mut().find(|(it, _)| it == &domain) {
+                Some(existing) => existing.1 = source,
+                None => sources.push((domain, source)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads a PEM file containing a PKCS#8 private key and the certificate chain (leaf first).
+pub fn load_pem(path: &Path) -> Result<CertifiedKey> {
+    let pems = parse_many(read(path)?)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, format!("{:?}", err)))?;
+    let mut key = None;
+    let mut chain = Vec::new();
+    for pem in pems {
+        if pem.tag.ends_with("PRIVATE KEY") {
+            key = Some(pem.contents);
+        } else if pem.tag == "CERTIFICATE" {
+            chain.push(rustls::Certificate(pem.contents));
+        }
+    }
+    let key = key.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing private key"))?;
+    if chain.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "Missing certificate"));
+    }
+    Ok(CertifiedKey::new(
+        chain,
+        any_supported_type(&PrivateKey(key))
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+    ))
+}
+
+// The private key is only readable by the service user.
+fn write_key(path: &Path, pem: &str) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(pem.as_bytes())
+}
+
+/// Creates a self-signed certificate for the given domains.
+/// The key is persisted in the state directory so that it survives restarts.
+pub fn self_signed(domains: Vec<String>) -> Result<CertifiedKey> {
+    let dir = Path::new(STATE_DIRECTORY.as_str());
+    let path = dir.join(SELF_SIGNED_KEY_FILE);
+    let key_pair = match read_to_string(&path) {
+        Ok(pem) => {
+            LogLevel::Info.log(|| println!("{}", "Restoring self-signed certificate key"));
+            KeyPair::from_pem(&pem).map_err(|err| Error::new(ErrorKind::InvalidData, err))?
+        }
+        Err(_) => {
+            LogLevel::Info.log(|| println!("{}", "Creating self-signed certificate key"));
+            let key_pair = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)
+                .map_err(|err| Error::new(ErrorKind::Unsupported, err))?;
+            if let Err(err) =
+                create_dir_all(dir).and_then(|_| write_key(&path, &key_pair.serialize_pem()))
+            {
+                LogLevel::Warning.log(|| {
+                    println!("{}", "Failed to persist self-signed certificate key".red());
+                    println!("{:?}", err);
+                });
+            }
+            key_pair
+        }
+    };
+    let mut params = CertificateParams::new(domains);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(key_pair);
+    let certificate =
+        Certificate::from_params(params).map_err(|err| Error::new(ErrorKind::Unsupported, err))?;
+    Ok(CertifiedKey::new(
+        vec![rustls::Certificate(
+            certificate
+                .serialize_der()
+                .map_err(|err| Error::new(ErrorKind::Unsupported, err))?,
+        )],
+        any_supported_type(&PrivateKey(certificate.serialize_private_key_der()))
+            .map_err(|err| Error::new(ErrorKind::Unsupported, err))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::source::{write_key, CertSource};
+    use std::fs::{metadata, read_to_string};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn acme() -> Vec<String> {
+        vec!["packurl.net".to_string(), "www.packurl.net".to_string()]
+    }
+
+    #[test]
+    fn parse() {
+        let acme = acme();
+        assert!(CertSource::parse("packurl.net", "acme", &acme).ok() == Some(CertSource::Acme));
+        assert!(CertSource::parse("example.com", "acme", &acme).is_err());
+        assert!(
+            CertSource::parse("example.com", "self-signed", &acme).ok()
+                == Some(CertSource::SelfSigned)
+        );
+        assert!(
+            CertSource::parse("example.com", "pem:/etc/example.pem", &acme).ok()
+                == Some(CertSource::Pem(PathBuf::from("/etc/example.pem")))
+        );
+        assert!(CertSource::parse("example.com", "pem:", &acme).is_err());
+        assert!(CertSource::parse("example.com", "letsencrypt", &acme).is_err());
+        assert!(CertSource::parse("example.com", "ACME", &acme).is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let acme = acme();
+        let mut sources = vec![
+            ("packurl.net".to_string(), CertSource::Acme),
+            ("example.com".to_string(), CertSource::SelfSigned),
+        ];
+        CertSource::apply(
+            &mut sources,
+            " packurl.net = self-signed,, Example.COM=pem:/etc/example.pem,www.packurl.net=acme",
+            &acme,
+        )
+        .unwrap();
+        assert!(
+            sources
+                == vec![
+                    ("packurl.net".to_string(), CertSource::SelfSigned),
+                    (
+                        "example.com".to_string(),
+                        CertSource::Pem(PathBuf::from("/etc/example.pem"))
+                    ),
+                    ("www.packurl.net".to_string(), CertSource::Acme),
+                ]
+        );
+    }
+
+    #[test]
+    fn malformed_overrides() {
+        let acme = acme();
+        let mut sources = Vec::new();
+        assert!(CertSource::apply(&mut sources, "example.com", &acme).is_err());
+        assert!(CertSource::apply(&mut sources, "example.com:self-signed", &acme).is_err());
+        assert!(CertSource::apply(&mut sources, "example.com=", &acme).is_err());
+        assert!(CertSource::apply(&mut sources, "example.com=acme", &acme).is_err());
+        assert!(CertSource::apply(&mut sources, "", &acme).is_ok());
+        assert!(sources.is_empty());
+    }
+
+    #[test]
+    fn key_permissions() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("self-signed.key.pem");
+        write_key(&path, "key").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "key");
+        assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}